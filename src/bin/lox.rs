@@ -1,22 +1,136 @@
 use std::{
-    error::Error,
-    fs, io,
+    fmt, fs, io,
     path::{Path, PathBuf},
+    process,
 };
 
 use rustyline::{error::ReadlineError, Editor};
 use structopt::StructOpt;
 
-use lox::compiler::compile;
+use lox::{
+    diagnostic::{Diagnostic, Severity},
+    interpret_with_options,
+    lexer::Position,
+    vm::InterpretError,
+    Options,
+};
+
+/// Exit codes from sysexits(3).
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+const EX_IOERR: i32 = 74;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "lox")]
 struct CommandLineArgs {
     /// Lox source file
     file: Option<PathBuf>,
+
+    /// Collect garbage before every allocation
+    #[structopt(long)]
+    stress_gc: bool,
+
+    /// Trace the execution of every instruction to stderr
+    #[structopt(long)]
+    trace: bool,
+
+    /// Only trace the function with this name, or the top-level code for
+    /// `<script>`
+    #[structopt(long, value_name = "NAME", requires = "trace")]
+    trace_function: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Print the bytecode of a script and of every function it declares
+    Disasm {
+        /// Lox source file
+        file: PathBuf,
+
+        /// Print the listing as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug)]
+enum Error {
+    Io(io::Error),
+    Readline(ReadlineError),
+    /// An error of the interpreted program, along with its source.
+    Interpret(InterpretError, String),
+}
+
+impl Error {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) | Error::Readline(_) => EX_IOERR,
+            Error::Interpret(InterpretError::CompileError(_), _) => EX_DATAERR,
+            Error::Interpret(InterpretError::InvalidChunk(_), _)
+            | Error::Interpret(InterpretError::RuntimeError { .. }, _) => EX_SOFTWARE,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Readline(err) => write!(f, "{}", err),
+            Error::Interpret(err, source) => write!(f, "{}", render(err, source)),
+        }
+    }
 }
 
-fn repl() {
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+impl From<ReadlineError> for Error {
+    fn from(error: ReadlineError) -> Error {
+        Error::Readline(error)
+    }
+}
+
+/// Renders compile errors with the source lines they point at, and runtime
+/// errors with the token that failed and their stack trace.
+fn render(error: &InterpretError, source: &str) -> String {
+    match error {
+        InterpretError::CompileError(diagnostics) => diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.render(source))
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        InterpretError::RuntimeError {
+            error,
+            line,
+            column,
+            trace,
+        } if *line > 0 => {
+            let position = Position::new(*line, *column);
+            let diagnostic = Diagnostic {
+                message: error.to_string(),
+                start: position,
+                end: position,
+                severity: Severity::Error,
+            };
+
+            let mut rendered = diagnostic.render(source);
+            for frame in trace {
+                rendered.push_str(&format!("\n{}", frame));
+            }
+            rendered
+        }
+        InterpretError::InvalidChunk(_) | InterpretError::RuntimeError { .. } => error.to_string(),
+    }
+}
+
+fn repl(options: &Options) -> Result<(), Error> {
     let mut rl = Editor::<()>::new();
 
     loop {
@@ -25,40 +139,57 @@ fn repl() {
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str());
-                compile(&line);
+                if let Err(err) = interpret_with_options(&line, options) {
+                    eprintln!("{}", render(&err, &line));
+                }
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
-                break;
+                return Ok(());
             }
             Err(ReadlineError::Eof) => {
                 println!("CTRL-D");
-                break;
-            }
-            Err(err) => {
-                println!("Error: {:?}", err);
-                break;
+                return Ok(());
             }
+            Err(err) => return Err(err.into()),
         }
     }
 }
 
-fn run_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+fn run_file<P: AsRef<Path>>(path: P, options: &Options) -> Result<(), Error> {
     let source = fs::read_to_string(path)?;
 
-    compile(&source);
+    interpret_with_options(&source, options).map_err(|err| Error::Interpret(err, source))
+}
+
+fn disassemble<P: AsRef<Path>>(path: P, json: bool) -> Result<(), Error> {
+    let source = fs::read_to_string(path)?;
 
-    Ok(())
+    match lox::disassemble(&source, json) {
+        Ok(listing) => {
+            print!("{}", listing);
+            Ok(())
+        }
+        Err(err) => Err(Error::Interpret(err, source)),
+    }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() {
     let args = CommandLineArgs::from_args();
+    let options = Options {
+        stress_gc: args.stress_gc,
+        trace: args.trace,
+        trace_function: args.trace_function,
+    };
 
-    if let Some(path) = args.file {
-        run_file(path)?;
-    } else {
-        repl();
-    }
+    let result = match (args.command, args.file) {
+        (Some(Command::Disasm { file, json }), _) => disassemble(file, json),
+        (None, Some(path)) => run_file(path, &options),
+        (None, None) => repl(&options),
+    };
 
-    Ok(())
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(err.exit_code());
+    }
 }
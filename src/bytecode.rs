@@ -1,34 +1,393 @@
-use std::fmt;
+use std::{
+    convert::TryFrom,
+    fmt,
+    io::{self, Write},
+    mem,
+};
 
-#[derive(Clone, Copy, Debug)]
+use crate::{
+    lexer::Position,
+    memory::Gc,
+    object::{BoundMethod, Class, Closure, Function, Instance, LoxString, NativeFn},
+};
+
+/// Instructions of the virtual machine.
+///
+/// In a chunk, each opcode is encoded as a byte, followed by its operands:
+/// - a constant index or a stack slot, as one byte,
+/// - a jump offset, as two bytes in big-endian order,
+/// - the constant index of `ConstantLong` and of the other `Long` opcodes, as
+///   three bytes in big-endian order,
+/// - the method name constant index, then the argument count for `Invoke` and
+///   `SuperInvoke`, and their long forms.
+#[repr(u8)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum OpCode {
-    Constant(usize),
+    Constant,
+    ConstantLong,
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal,
+    SetLocal,
+    DefineGlobal,
+    DefineGlobalLong,
+    GetGlobal,
+    GetGlobalLong,
+    SetGlobal,
+    SetGlobalLong,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    GetPropertyLong,
+    SetProperty,
+    SetPropertyLong,
+    GetSuper,
+    GetSuperLong,
+    Equal,
+    Greater,
+    Less,
     Add,
     Substract,
     Multiply,
     Divide,
+    Not,
     Negate,
+    Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Invoke,
+    InvokeLong,
+    SuperInvoke,
+    SuperInvokeLong,
+    Closure,
+    ClosureLong,
+    CloseUpvalue,
     Return,
+    Class,
+    ClassLong,
+    Inherit,
+    Method,
+    MethodLong,
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Every opcode, indexed by its encoding.
+const OP_CODES: [OpCode; 49] = [
+    OpCode::Constant,
+    OpCode::ConstantLong,
+    OpCode::Nil,
+    OpCode::True,
+    OpCode::False,
+    OpCode::Pop,
+    OpCode::GetLocal,
+    OpCode::SetLocal,
+    OpCode::DefineGlobal,
+    OpCode::DefineGlobalLong,
+    OpCode::GetGlobal,
+    OpCode::GetGlobalLong,
+    OpCode::SetGlobal,
+    OpCode::SetGlobalLong,
+    OpCode::GetUpvalue,
+    OpCode::SetUpvalue,
+    OpCode::GetProperty,
+    OpCode::GetPropertyLong,
+    OpCode::SetProperty,
+    OpCode::SetPropertyLong,
+    OpCode::GetSuper,
+    OpCode::GetSuperLong,
+    OpCode::Equal,
+    OpCode::Greater,
+    OpCode::Less,
+    OpCode::Add,
+    OpCode::Substract,
+    OpCode::Multiply,
+    OpCode::Divide,
+    OpCode::Not,
+    OpCode::Negate,
+    OpCode::Print,
+    OpCode::Jump,
+    OpCode::JumpIfFalse,
+    OpCode::Loop,
+    OpCode::Call,
+    OpCode::Invoke,
+    OpCode::InvokeLong,
+    OpCode::SuperInvoke,
+    OpCode::SuperInvokeLong,
+    OpCode::Closure,
+    OpCode::ClosureLong,
+    OpCode::CloseUpvalue,
+    OpCode::Return,
+    OpCode::Class,
+    OpCode::ClassLong,
+    OpCode::Inherit,
+    OpCode::Method,
+    OpCode::MethodLong,
+];
+
+impl OpCode {
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        OP_CODES.get(byte as usize).cloned()
+    }
+
+    /// Number of bytes of the instruction, opcode and operands included.
+    pub fn width(self) -> usize {
+        match self {
+            OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Pop
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Substract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return
+            | OpCode::Inherit => 1,
+            OpCode::Constant
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Call
+            | OpCode::Closure
+            | OpCode::Class
+            | OpCode::Method => 2,
+            OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop
+            | OpCode::Invoke
+            | OpCode::SuperInvoke => 3,
+            OpCode::ConstantLong
+            | OpCode::DefineGlobalLong
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::GetPropertyLong
+            | OpCode::SetPropertyLong
+            | OpCode::GetSuperLong
+            | OpCode::ClosureLong
+            | OpCode::ClassLong
+            | OpCode::MethodLong => 4,
+            OpCode::InvokeLong | OpCode::SuperInvokeLong => 5,
+        }
+    }
+
+    /// Form of the opcode taking a three bytes constant index, for opcodes
+    /// whose first operand is a one byte constant index.
+    pub fn long(self) -> Option<OpCode> {
+        match self {
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::GetSuper => Some(OpCode::GetSuperLong),
+            OpCode::Invoke => Some(OpCode::InvokeLong),
+            OpCode::SuperInvoke => Some(OpCode::SuperInvokeLong),
+            OpCode::Closure => Some(OpCode::ClosureLong),
+            OpCode::Class => Some(OpCode::ClassLong),
+            OpCode::Method => Some(OpCode::MethodLong),
+            _ => None,
+        }
+    }
+
+    /// Whether the first operand of the instruction is a three bytes constant
+    /// index.
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::GetSuperLong
+                | OpCode::InvokeLong
+                | OpCode::SuperInvokeLong
+                | OpCode::ClosureLong
+                | OpCode::ClassLong
+                | OpCode::MethodLong
+        )
+    }
+
+    /// Name of the opcode in listings.
+    pub fn name(self) -> &'static str {
+        match self {
+            OpCode::Constant => "OP_CONSTANT",
+            OpCode::ConstantLong => "OP_CONSTANT_LONG",
+            OpCode::Nil => "OP_NIL",
+            OpCode::True => "OP_TRUE",
+            OpCode::False => "OP_FALSE",
+            OpCode::Pop => "OP_POP",
+            OpCode::GetLocal => "OP_GET_LOCAL",
+            OpCode::SetLocal => "OP_SET_LOCAL",
+            OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+            OpCode::DefineGlobalLong => "OP_DEFINE_GLOBAL_LONG",
+            OpCode::GetGlobal => "OP_GET_GLOBAL",
+            OpCode::GetGlobalLong => "OP_GET_GLOBAL_LONG",
+            OpCode::SetGlobal => "OP_SET_GLOBAL",
+            OpCode::SetGlobalLong => "OP_SET_GLOBAL_LONG",
+            OpCode::GetUpvalue => "OP_GET_UPVALUE",
+            OpCode::SetUpvalue => "OP_SET_UPVALUE",
+            OpCode::GetProperty => "OP_GET_PROPERTY",
+            OpCode::GetPropertyLong => "OP_GET_PROPERTY_LONG",
+            OpCode::SetProperty => "OP_SET_PROPERTY",
+            OpCode::SetPropertyLong => "OP_SET_PROPERTY_LONG",
+            OpCode::GetSuper => "OP_GET_SUPER",
+            OpCode::GetSuperLong => "OP_GET_SUPER_LONG",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::Less => "OP_LESS",
+            OpCode::Add => "OP_ADD",
+            OpCode::Substract => "OP_SUBSTRACT",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Not => "OP_NOT",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::Print => "OP_PRINT",
+            OpCode::Jump => "OP_JUMP",
+            OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+            OpCode::Loop => "OP_LOOP",
+            OpCode::Call => "OP_CALL",
+            OpCode::Invoke => "OP_INVOKE",
+            OpCode::InvokeLong => "OP_INVOKE_LONG",
+            OpCode::SuperInvoke => "OP_SUPER_INVOKE",
+            OpCode::SuperInvokeLong => "OP_SUPER_INVOKE_LONG",
+            OpCode::Closure => "OP_CLOSURE",
+            OpCode::ClosureLong => "OP_CLOSURE_LONG",
+            OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+            OpCode::Return => "OP_RETURN",
+            OpCode::Class => "OP_CLASS",
+            OpCode::ClassLong => "OP_CLASS_LONG",
+            OpCode::Inherit => "OP_INHERIT",
+            OpCode::Method => "OP_METHOD",
+            OpCode::MethodLong => "OP_METHOD_LONG",
+        }
+    }
+
+    /// Whether the first operand of the instruction is a constant index.
+    fn has_constant(self) -> bool {
+        matches!(
+            self,
+            OpCode::Constant
+                | OpCode::DefineGlobal
+                | OpCode::GetGlobal
+                | OpCode::SetGlobal
+                | OpCode::GetProperty
+                | OpCode::SetProperty
+                | OpCode::GetSuper
+                | OpCode::Invoke
+                | OpCode::SuperInvoke
+                | OpCode::Closure
+                | OpCode::Class
+                | OpCode::Method
+        ) || self.is_long()
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Value {
+    Bool(bool),
+    Nil,
     Number(f64),
+    String(Gc<LoxString>),
+    Function(Gc<Function>),
+    Closure(Gc<Closure>),
+    Class(Gc<Class>),
+    Instance(Gc<Instance>),
+    BoundMethod(Gc<BoundMethod>),
+    NativeFn(Gc<NativeFn>),
+}
+
+impl Value {
+    /// Only `nil` and `false` are falsey, every other value is truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::Closure(_) | Value::BoundMethod(_) | Value::NativeFn(_) => {
+                "function"
+            }
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+        }
+    }
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Nil => write!(f, "nil"),
             Value::Number(value) => write!(f, "{}", value),
+            Value::String(value) => write!(f, "{}", value),
+            Value::Function(value) => write!(f, "{}", value),
+            Value::Closure(value) => write!(f, "{}", value),
+            Value::Class(value) => write!(f, "{}", value),
+            Value::Instance(value) => write!(f, "{}", value),
+            Value::BoundMethod(value) => write!(f, "{}", value),
+            Value::NativeFn(value) => write!(f, "{}", value),
         }
     }
 }
 
+/// Run of consecutive bytes of code compiled from the same line, or from
+/// tokens at the same column.
+#[derive(Clone, Copy, Debug)]
+struct Run {
+    /// Offset of the first byte of the run.
+    start: u32,
+    value: u32,
+}
+
+/// Appends `value` for the next byte of code at `offset` to `runs`.
+fn push_run(runs: &mut Vec<Run>, offset: usize, value: usize) {
+    let value = u32::try_from(value).unwrap_or(u32::MAX);
+    match runs.last() {
+        Some(run) if run.value == value => {}
+        _ => runs.push(Run {
+            start: u32::try_from(offset).expect("chunk exceeds 4 GiB"),
+            value,
+        }),
+    }
+}
+
+/// Returns the value of the run holding the byte at `offset`.
+fn run_at(runs: &[Run], offset: usize) -> usize {
+    let run = runs.partition_point(|run| run.start as usize <= offset);
+    runs[run - 1].value as usize
+}
+
 #[derive(Clone, Default, Debug)]
 pub struct Chunk {
-    pub code: Vec<OpCode>,
+    pub code: Vec<u8>,
     pub constants: Vec<Value>,
-    lines: Vec<usize>,
+    /// Source lines of the code, run-length encoded by increasing offset.
+    lines: Vec<Run>,
+    /// Source columns of the code, encoded the same way.
+    columns: Vec<Run>,
 }
 
 impl Chunk {
@@ -37,55 +396,417 @@ impl Chunk {
             code: Vec::new(),
             constants: Vec::new(),
             lines: Vec::new(),
+            columns: Vec::new(),
         }
     }
 
-    pub fn write(&mut self, op_code: OpCode, line: usize) {
-        self.code.push(op_code);
-        self.lines.push(line);
+    pub fn write(&mut self, byte: u8, position: Position) {
+        push_run(&mut self.lines, self.code.len(), position.line);
+        push_run(&mut self.columns, self.code.len(), position.column);
+        self.code.push(byte);
+    }
+
+    /// Returns the source position of the token the byte at `offset` was
+    /// compiled from.
+    pub fn position_at(&self, offset: usize) -> Option<Position> {
+        if offset >= self.code.len() {
+            return None;
+        }
+
+        let line = run_at(&self.lines, offset);
+        let column = run_at(&self.columns, offset);
+        Some(Position::new(line, column))
+    }
+
+    /// Returns the source line of the instruction at `offset`.
+    pub fn line_at(&self, offset: usize) -> Option<usize> {
+        if offset >= self.code.len() {
+            return None;
+        }
+
+        Some(run_at(&self.lines, offset))
     }
 
     pub fn code_at(&self, offset: usize) -> Option<OpCode> {
-        self.code.get(offset).cloned()
+        self.code.get(offset).cloned().and_then(OpCode::from_byte)
+    }
+
+    pub fn read_byte(&self, offset: usize) -> u8 {
+        self.code[offset]
+    }
+
+    pub fn read_short(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    pub fn read_long(&self, offset: usize) -> usize {
+        u32::from_be_bytes([
+            0,
+            self.code[offset],
+            self.code[offset + 1],
+            self.code[offset + 2],
+        ]) as usize
     }
 
     pub fn constant_at(&self, offset: usize) -> Option<Value> {
         self.constants.get(offset).cloned()
     }
 
+    /// Number of bytes owned by the chunk.
+    pub fn size(&self) -> usize {
+        self.code.capacity()
+            + self.constants.capacity() * mem::size_of::<Value>()
+            + (self.lines.capacity() + self.columns.capacity()) * mem::size_of::<Run>()
+    }
+
     pub fn push_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
         self.constants.len() - 1
     }
 
-    pub fn disassemble(&self, name: &str) {
-        println!("== {} == ", name);
+    /// Functions compiled in the chunk, directly or nested in one another,
+    /// in the order they were declared.
+    pub fn functions(&self) -> Vec<Gc<Function>> {
+        let mut functions = Vec::new();
+        for constant in &self.constants {
+            if let Value::Function(function) = constant {
+                functions.push(*function);
+                functions.extend(function.chunk.functions());
+            }
+        }
+
+        functions
+    }
 
-        for offset in 0..self.code.len() {
-            disassemble_instruction(self, offset);
+    /// Writes a listing of the chunk, one instruction per line.
+    pub fn disassemble<W: Write + ?Sized>(&self, name: &str, out: &mut W) -> io::Result<()> {
+        writeln!(out, "== {} ==", name)?;
+
+        let mut offset = 0;
+        while offset < self.code.len() {
+            offset += disassemble_instruction(self, offset, out)?;
         }
+
+        Ok(())
+    }
+
+    /// Returns the listing written by `disassemble`.
+    pub fn disassemble_to_string(&self, name: &str) -> String {
+        let mut listing = Vec::new();
+        self.disassemble(name, &mut listing)
+            .expect("writing to a vector cannot fail");
+
+        String::from_utf8(listing).expect("listings are valid UTF-8")
+    }
+
+    /// Writes a listing of the chunk as a JSON object, with the name of the
+    /// chunk and the list of its decoded instructions.
+    pub fn disassemble_json<W: Write + ?Sized>(&self, name: &str, out: &mut W) -> io::Result<()> {
+        write!(out, "{{\"name\":")?;
+        write_json_string(out, name)?;
+        write!(out, ",\"code\":[")?;
+
+        let mut offset = 0;
+        while offset < self.code.len() {
+            if offset > 0 {
+                write!(out, ",")?;
+            }
+            offset += instruction_json(self, offset, out)?;
+        }
+
+        write!(out, "]}}")
     }
 }
 
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize) {
-    print!("{:04} ", offset);
+/// Decodes the operands of the instruction at `offset`.
+fn operands(chunk: &Chunk, op_code: OpCode, offset: usize) -> Vec<usize> {
+    match op_code {
+        OpCode::InvokeLong | OpCode::SuperInvokeLong => vec![
+            chunk.read_long(offset + 1),
+            chunk.read_byte(offset + 4) as usize,
+        ],
+        _ if op_code.is_long() => vec![chunk.read_long(offset + 1)],
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            vec![chunk.read_short(offset + 1) as usize]
+        }
+        _ => (1..op_code.width())
+            .map(|operand| chunk.read_byte(offset + operand) as usize)
+            .collect(),
+    }
+}
 
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        print!("   | ");
+/// Offset of the instruction a jump at `offset` leads to.
+fn jump_target(op_code: OpCode, offset: usize, jump: usize) -> usize {
+    let next = offset + op_code.width();
+    if op_code == OpCode::Loop {
+        next.saturating_sub(jump)
     } else {
-        print!("{:4} ", chunk.lines[offset]);
-    }
-
-    match chunk.code[offset] {
-        OpCode::Constant(offset) => println!(
-            "{:-16} {:4} '{}'",
-            "OP_CONSTANT", offset, chunk.constants[offset]
-        ),
-        OpCode::Add => println!("OP_ADD"),
-        OpCode::Substract => println!("OP_SUBSTRACT"),
-        OpCode::Multiply => println!("OP_MULTIPLY"),
-        OpCode::Divide => println!("OP_DIVIDE"),
-        OpCode::Negate => println!("OP_NEGATE"),
-        OpCode::Return => println!("OP_RETURN"),
+        next + jump
+    }
+}
+
+/// Writes the instruction at `offset`, and returns its width.
+pub fn disassemble_instruction<W: Write + ?Sized>(
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut W,
+) -> io::Result<usize> {
+    write!(out, "{:04} ", offset)?;
+
+    let line = chunk.line_at(offset).unwrap_or_default();
+    if offset > 0 && chunk.line_at(offset - 1) == Some(line) {
+        write!(out, "   | ")?;
+    } else {
+        write!(out, "{:4} ", line)?;
+    }
+
+    let op_code = match chunk.code_at(offset) {
+        Some(op_code) => op_code,
+        None => {
+            writeln!(out, "Unknown opcode {}", chunk.code[offset])?;
+            return Ok(1);
+        }
+    };
+
+    let name = op_code.name();
+    let operands = operands(chunk, op_code, offset);
+    match op_code {
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            let target = jump_target(op_code, offset, operands[0]);
+            writeln!(out, "{:-16} {:4} -> {}", name, offset, target)?;
+        }
+        OpCode::Invoke | OpCode::SuperInvoke | OpCode::InvokeLong | OpCode::SuperInvokeLong => {
+            let constant = operands[0];
+            writeln!(
+                out,
+                "{:-16} ({} args) {:4} '{}'",
+                name, operands[1], constant, chunk.constants[constant]
+            )?;
+        }
+        OpCode::Closure | OpCode::ClosureLong => {
+            let constant = operands[0];
+            let value = chunk.constants[constant];
+            writeln!(out, "{:-16} {:4} {}", name, constant, value)?;
+
+            if let Value::Function(function) = value {
+                for upvalue in &function.upvalues {
+                    let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                    writeln!(
+                        out,
+                        "{:04}      |                     {} {}",
+                        offset, kind, upvalue.index
+                    )?;
+                }
+            }
+        }
+        _ if op_code.has_constant() => {
+            let constant = operands[0];
+            writeln!(
+                out,
+                "{:-16} {:4} '{}'",
+                name, constant, chunk.constants[constant]
+            )?;
+        }
+        _ => match operands.first() {
+            Some(operand) => writeln!(out, "{:-16} {:4}", name, operand)?,
+            None => writeln!(out, "{}", name)?,
+        },
+    }
+
+    Ok(op_code.width())
+}
+
+/// Writes the instruction at `offset` as a JSON object, and returns its width.
+fn instruction_json<W: Write + ?Sized>(
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut W,
+) -> io::Result<usize> {
+    let position = chunk.position_at(offset).unwrap_or_default();
+    write!(
+        out,
+        "{{\"offset\":{},\"line\":{},\"column\":{},\"opcode\":",
+        offset, position.line, position.column
+    )?;
+
+    let op_code = match chunk.code_at(offset) {
+        Some(op_code) => op_code,
+        None => {
+            write!(out, "null,\"byte\":{}}}", chunk.code[offset])?;
+            return Ok(1);
+        }
+    };
+    write_json_string(out, op_code.name())?;
+
+    let operands = operands(chunk, op_code, offset);
+    write!(out, ",\"operands\":[")?;
+    for (idx, operand) in operands.iter().enumerate() {
+        if idx > 0 {
+            write!(out, ",")?;
+        }
+        write!(out, "{}", operand)?;
+    }
+    write!(out, "]")?;
+
+    if op_code.has_constant() {
+        let value = chunk.constants[operands[0]];
+        write!(out, ",\"constant\":")?;
+        write_json_string(out, &value.to_string())?;
+
+        if let (OpCode::Closure | OpCode::ClosureLong, Value::Function(function)) = (op_code, value)
+        {
+            write!(out, ",\"upvalues\":[")?;
+            for (idx, upvalue) in function.upvalues.iter().enumerate() {
+                if idx > 0 {
+                    write!(out, ",")?;
+                }
+                write!(
+                    out,
+                    "{{\"local\":{},\"index\":{}}}",
+                    upvalue.is_local, upvalue.index
+                )?;
+            }
+            write!(out, "]")?;
+        }
+    }
+
+    if let OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop = op_code {
+        write!(
+            out,
+            ",\"target\":{}",
+            jump_target(op_code, offset, operands[0])
+        )?;
+    }
+
+    write!(out, "}}")?;
+    Ok(op_code.width())
+}
+
+fn write_json_string<W: Write + ?Sized>(out: &mut W, value: &str) -> io::Result<()> {
+    write!(out, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(out, "\\\"")?,
+            '\\' => write!(out, "\\\\")?,
+            '\n' => write!(out, "\\n")?,
+            '\r' => write!(out, "\\r")?,
+            '\t' => write!(out, "\\t")?,
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    write!(out, "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, memory::Heap};
+
+    #[test]
+    fn line_table() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Constant as u8, Position::new(1, 7));
+        chunk.write(0, Position::new(1, 7));
+        chunk.write(OpCode::Print as u8, Position::new(1, 1));
+        chunk.write(OpCode::Nil as u8, Position::new(3, 1));
+        chunk.write(OpCode::Return as u8, Position::new(3, 1));
+
+        assert_eq!(chunk.lines.len(), 2);
+        assert_eq!(chunk.columns.len(), 2);
+        assert_eq!(chunk.position_at(0), Some(Position::new(1, 7)));
+        assert_eq!(chunk.position_at(1), Some(Position::new(1, 7)));
+        assert_eq!(chunk.position_at(2), Some(Position::new(1, 1)));
+        assert_eq!(chunk.line_at(4), Some(3));
+        assert_eq!(chunk.line_at(5), None);
+    }
+
+    #[test]
+    fn line_table_size() {
+        let source: String = (0..40)
+            .map(|i| format!("var v{} = {} * 2 + (v{} - 1) / 3;\n", i, i, i.max(1) - 1))
+            .collect();
+        let mut heap = Heap::new();
+        let chunk = compile(&format!("var v0 = 0;\n{}", source), &mut heap).unwrap();
+
+        assert!(chunk.lines.len() <= 42, "{} runs", chunk.lines.len());
+        // Smaller than a table of the line of every byte.
+        let table_size = (chunk.lines.len() + chunk.columns.len()) * mem::size_of::<Run>();
+        assert!(table_size < chunk.code.len() * mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn wide_columns() {
+        let source = format!("{}print -nil;", " ".repeat(70_000));
+        let mut heap = Heap::new();
+        let chunk = compile(&source, &mut heap).unwrap();
+
+        assert_eq!(chunk.position_at(1), Some(Position::new(1, 70_007)));
+        assert_eq!(chunk.position_at(2), Some(Position::new(1, 70_011)));
+    }
+
+    #[test]
+    fn disassemble() {
+        let mut heap = Heap::new();
+        let chunk = compile("print 1 +\n 2;", &mut heap).unwrap();
+
+        assert_eq!(
+            chunk.disassemble_to_string("test"),
+            "== test ==
+0000    1 OP_CONSTANT         0 '1'
+0002    2 OP_CONSTANT         1 '2'
+0004    1 OP_ADD
+0005    2 OP_PRINT
+0006    | OP_NIL
+0007    | OP_RETURN
+"
+        );
+    }
+
+    #[test]
+    fn disassemble_json() {
+        let mut heap = Heap::new();
+        let chunk = compile("while (false) print \"\\\";", &mut heap).unwrap();
+
+        let mut listing = Vec::new();
+        chunk.disassemble_json("test", &mut listing).unwrap();
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            concat!(
+                r#"{"name":"test","code":["#,
+                r#"{"offset":0,"line":1,"column":8,"opcode":"OP_FALSE","operands":[]},"#,
+                r#"{"offset":1,"line":1,"column":13,"opcode":"OP_JUMP_IF_FALSE","operands":[7],"target":11},"#,
+                r#"{"offset":4,"line":1,"column":13,"opcode":"OP_POP","operands":[]},"#,
+                r#"{"offset":5,"line":1,"column":21,"opcode":"OP_CONSTANT","operands":[0],"constant":"\\"},"#,
+                r#"{"offset":7,"line":1,"column":24,"opcode":"OP_PRINT","operands":[]},"#,
+                r#"{"offset":8,"line":1,"column":24,"opcode":"OP_LOOP","operands":[11],"target":0},"#,
+                r#"{"offset":11,"line":1,"column":24,"opcode":"OP_POP","operands":[]},"#,
+                r#"{"offset":12,"line":1,"column":24,"opcode":"OP_NIL","operands":[]},"#,
+                r#"{"offset":13,"line":1,"column":24,"opcode":"OP_RETURN","operands":[]}]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn disassemble_json_long_closure() {
+        let literals: String = (0..300).map(|i| format!("{};", i)).collect();
+        let source = format!("fun f() {{ var a; {} fun g() {{ a; }} }}", literals);
+        let mut heap = Heap::new();
+        let chunk = compile(&source, &mut heap).unwrap();
+
+        let mut listing = Vec::new();
+        chunk.functions()[0]
+            .chunk
+            .disassemble_json("f", &mut listing)
+            .unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        assert!(
+            listing.contains(concat!(
+                r#""opcode":"OP_CLOSURE_LONG","operands":[300],"constant":"<fn g>","#,
+                r#""upvalues":[{"local":true,"index":1}]}"#,
+            )),
+            "{}",
+            listing
+        );
     }
 }
@@ -1,9 +1,1830 @@
-use crate::lexer::Scanner;
+use std::{collections::HashMap, convert::TryFrom};
 
-pub fn compile(source: &str) {
-    let mut scanner = Scanner::init(source);
+use crate::{
+    bytecode::{Chunk, OpCode, Value},
+    diagnostic::Diagnostic,
+    lexer::{Position, Scanner, Token, TokenKind},
+    memory::{Gc, Heap, Trace},
+    object::{Function, LoxString, UpvalueDescriptor},
+    vm::InterpretError,
+};
 
-    while let Some(token) = scanner.next_token() {
-        println!("{}", token);
+type CompileResult<T> = Result<T, Diagnostic>;
+
+/// Compiles `source` into a chunk whose objects live on `heap`.
+///
+/// The chunk is only valid as long as `heap` is alive and it is rooted
+/// whenever `heap` collects garbage, which only the crate can guarantee.
+pub(crate) fn compile(source: &str, heap: &mut Heap) -> Result<Chunk, InterpretError> {
+    let compiler = Compiler::init(source, heap);
+
+    compiler.compile().map_err(InterpretError::CompileError)
+}
+
+#[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    fn next(self) -> Self {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call => Precedence::Primary,
+            Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool) -> CompileResult<()>;
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    fn new(
+        prefix: Option<ParseFn<'a>>,
+        infix: Option<ParseFn<'a>>,
+        precedence: Precedence,
+    ) -> Self {
+        ParseRule {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+fn get_rule<'a>(kind: TokenKind) -> ParseRule<'a> {
+    match kind {
+        TokenKind::LeftParen => ParseRule::new(
+            Some(Compiler::grouping),
+            Some(Compiler::call),
+            Precedence::Call,
+        ),
+        TokenKind::Minus => ParseRule::new(
+            Some(Compiler::unary),
+            Some(Compiler::binary),
+            Precedence::Term,
+        ),
+        TokenKind::Plus => ParseRule::new(None, Some(Compiler::binary), Precedence::Term),
+        TokenKind::Slash => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+        TokenKind::Star => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+        TokenKind::Bang => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
+        TokenKind::BangEqual => ParseRule::new(None, Some(Compiler::binary), Precedence::Equality),
+        TokenKind::EqualEqual => ParseRule::new(None, Some(Compiler::binary), Precedence::Equality),
+        TokenKind::Greater => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+        TokenKind::GreaterEqual => {
+            ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison)
+        }
+        TokenKind::Less => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+        TokenKind::LessEqual => {
+            ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison)
+        }
+        TokenKind::Identifier => ParseRule::new(Some(Compiler::variable), None, Precedence::None),
+        TokenKind::String => ParseRule::new(Some(Compiler::string), None, Precedence::None),
+        TokenKind::Number => ParseRule::new(Some(Compiler::number), None, Precedence::None),
+        TokenKind::And => ParseRule::new(None, Some(Compiler::and), Precedence::And),
+        TokenKind::Or => ParseRule::new(None, Some(Compiler::or), Precedence::Or),
+        TokenKind::False => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        TokenKind::Nil => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        TokenKind::True => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        TokenKind::Dot => ParseRule::new(None, Some(Compiler::dot), Precedence::Call),
+        TokenKind::This => ParseRule::new(Some(Compiler::this), None, Precedence::None),
+        TokenKind::Super => ParseRule::new(Some(Compiler::super_), None, Precedence::None),
+        _ => ParseRule::new(None, None, Precedence::None),
+    }
+}
+
+/// Maximum number of locals in scope at once, as slots are addressed by a byte.
+const MAX_LOCALS: usize = u8::MAX as usize + 1;
+
+/// Maximum number of variables a function can capture.
+const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+
+/// Maximum number of constants in a chunk, as their index is at most three bytes.
+const MAX_CONSTANTS: usize = 1 << 24;
+
+/// Maximum number of parameters of a function, and of arguments of a call.
+const MAX_ARITY: usize = u8::MAX as usize;
+
+struct Local<'a> {
+    name: Token<'a>,
+    /// Scope depth of the local, `None` until its initializer has been compiled.
+    depth: Option<usize>,
+    /// Whether a closure captures the local, which must then outlive its scope.
+    is_captured: bool,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum FunctionKind {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// Compilation state of a class body, one per class being compiled.
+struct ClassState {
+    /// Whether the class inherits from another one, making `super` usable.
+    has_superclass: bool,
+}
+
+/// Compilation state of a function body, one per function being compiled.
+struct FunctionState<'a> {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local<'a>>,
+    scope_depth: usize,
+    /// Constants holding the names of identifiers, by name.
+    names: HashMap<Gc<LoxString>, usize>,
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(kind: FunctionKind, name: Option<Gc<LoxString>>) -> Self {
+        // The first stack slot of every call frame holds the function itself,
+        // or the receiver of methods, which is accessible as `this`.
+        let callee_name = match kind {
+            FunctionKind::Initializer | FunctionKind::Method => "this",
+            FunctionKind::Function | FunctionKind::Script => "",
+        };
+        let callee = Local {
+            name: Token::new(TokenKind::Identifier, callee_name, Position::init()),
+            depth: Some(0),
+            is_captured: false,
+        };
+
+        FunctionState {
+            function: Function::new(name),
+            kind,
+            locals: vec![callee],
+            scope_depth: 0,
+            names: HashMap::new(),
+        }
+    }
+}
+
+struct Compiler<'a> {
+    scanner: Scanner<'a>,
+    current: Token<'a>,
+    previous: Token<'a>,
+    heap: &'a mut Heap,
+    functions: Vec<FunctionState<'a>>,
+    /// Classes being compiled, innermost last.
+    classes: Vec<ClassState>,
+    /// Errors reported so far, compilation going on after each of them.
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Nesting of the compiler before a declaration, restored if it fails.
+struct Checkpoint {
+    functions: usize,
+    classes: usize,
+    locals: usize,
+    scope_depth: usize,
+}
+
+impl<'a> Compiler<'a> {
+    fn init(source: &'a str, heap: &'a mut Heap) -> Self {
+        let eof = Token::new(TokenKind::Eof, "", Position::init());
+
+        Compiler {
+            scanner: Scanner::init(source),
+            current: eof,
+            previous: eof,
+            heap,
+            functions: vec![FunctionState::new(FunctionKind::Script, None)],
+            classes: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn compile(mut self) -> Result<Chunk, Vec<Diagnostic>> {
+        if let Err(diagnostic) = self.advance() {
+            self.diagnostics.push(diagnostic);
+            self.synchronize();
+        }
+        while !self.check(TokenKind::Eof) {
+            self.declaration();
+        }
+        let script = self.end();
+
+        if self.diagnostics.is_empty() {
+            Ok(script.chunk)
+        } else {
+            Err(self.diagnostics)
+        }
+    }
+
+    fn state(&self) -> &FunctionState<'a> {
+        self.functions.last().expect("no function being compiled")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState<'a> {
+        self.functions
+            .last_mut()
+            .expect("no function being compiled")
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.state().function.chunk
+    }
+
+    fn chunk_mut(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
+    fn advance(&mut self) -> CompileResult<()> {
+        self.previous = self.current;
+
+        loop {
+            self.current = match self.scanner.next_token() {
+                Some(token) => token,
+                None => Token::new(TokenKind::Eof, "", self.previous.end()),
+            };
+
+            match self.current.kind {
+                TokenKind::Comment => continue,
+                TokenKind::UnterminatedStringError => {
+                    return Err(self.error_at_current("Unterminated string."))
+                }
+                TokenKind::UnexpectedCharacterError => {
+                    return Err(self.error_at_current("Unexpected character."))
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn check(&self, kind: TokenKind) -> bool {
+        self.current.kind == kind
+    }
+
+    fn matches(&mut self, kind: TokenKind) -> CompileResult<bool> {
+        if self.check(kind) {
+            self.advance()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn consume(&mut self, kind: TokenKind, message: &str) -> CompileResult<()> {
+        if self.current.kind == kind {
+            self.advance()
+        } else {
+            Err(self.error_at_current(message))
+        }
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let position = self.previous.position;
+        self.emit_byte_at(byte, position);
+    }
+
+    /// Emits a byte attributed to the token at `position`, for instructions
+    /// emitted after their operands were compiled.
+    fn emit_byte_at(&mut self, byte: u8, position: Position) {
+        self.chunk_mut().write(byte, position);
+    }
+
+    fn emit(&mut self, op_code: OpCode) {
+        self.emit_byte(op_code as u8);
+    }
+
+    /// Emits an instruction with a one-byte operand.
+    fn emit_with(&mut self, op_code: OpCode, operand: u8) {
+        self.emit(op_code);
+        self.emit_byte(operand);
+    }
+
+    /// Emits an instruction whose first operand is a constant index, in its
+    /// long form if the index doesn't fit in a byte.
+    fn emit_indexed(&mut self, op_code: OpCode, index: usize) {
+        let position = self.previous.position;
+        self.emit_indexed_at(op_code, index, position);
+    }
+
+    fn emit_indexed_at(&mut self, op_code: OpCode, index: usize, position: Position) {
+        if let Ok(index) = u8::try_from(index) {
+            self.emit_byte_at(op_code as u8, position);
+            self.emit_byte_at(index, position);
+        } else {
+            let long = op_code.long().expect("operand too large for the opcode");
+            let [_, high, middle, low] = (index as u32).to_be_bytes();
+            self.emit_byte_at(long as u8, position);
+            self.emit_byte_at(high, position);
+            self.emit_byte_at(middle, position);
+            self.emit_byte_at(low, position);
+        }
+    }
+
+    fn emit_constant(&mut self, value: Value) -> CompileResult<()> {
+        let constant = self.make_constant(value)?;
+        self.emit_indexed(OpCode::Constant, constant);
+
+        Ok(())
+    }
+
+    fn make_constant(&mut self, value: Value) -> CompileResult<usize> {
+        if self.chunk().constants.len() == MAX_CONSTANTS {
+            return Err(self.error("Too many constants in one chunk."));
+        }
+
+        Ok(self.chunk_mut().push_constant(value))
+    }
+
+    fn emit_return(&mut self) {
+        // Initializers implicitly return the new instance.
+        if self.state().kind == FunctionKind::Initializer {
+            self.emit_with(OpCode::GetLocal, 0);
+        } else {
+            self.emit(OpCode::Nil);
+        }
+        self.emit(OpCode::Return);
+    }
+
+    /// Emits a jump instruction with a placeholder offset, to be backpatched
+    /// with `patch_jump` once the target is known. Returns the offset of the
+    /// placeholder.
+    fn emit_jump(&mut self, op_code: OpCode) -> usize {
+        self.emit(op_code);
+        self.emit_byte(u8::MAX);
+        self.emit_byte(u8::MAX);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) -> CompileResult<()> {
+        // The jump lands right after its two-byte operand.
+        let jump = match u16::try_from(self.chunk().code.len() - offset - 2) {
+            Ok(jump) => jump,
+            Err(_) => return Err(self.error("Too much code to jump over.")),
+        };
+
+        let [high, low] = jump.to_be_bytes();
+        let code = &mut self.chunk_mut().code;
+        code[offset] = high;
+        code[offset + 1] = low;
+
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) -> CompileResult<()> {
+        // The jump goes back from right after the loop instruction.
+        let offset = match u16::try_from(self.chunk().code.len() + 3 - loop_start) {
+            Ok(offset) => offset,
+            Err(_) => return Err(self.error("Loop body too large.")),
+        };
+
+        let [high, low] = offset.to_be_bytes();
+        self.emit(OpCode::Loop);
+        self.emit_byte(high);
+        self.emit_byte(low);
+
+        Ok(())
+    }
+
+    /// Allocates an object on the heap, collecting garbage first if needed.
+    ///
+    /// The object's own references are marked as roots of the collection
+    /// along with the functions being compiled.
+    fn alloc<T: Trace + 'static>(&mut self, value: T) -> Gc<T> {
+        if self.heap.should_collect() {
+            value.trace(self.heap);
+            self.collect_garbage();
+        }
+
+        self.heap.alloc(value)
+    }
+
+    /// Like `alloc`, for interned strings.
+    fn copy_string(&mut self, value: &str) -> Gc<LoxString> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        self.heap.copy_string(value)
+    }
+
+    fn collect_garbage(&mut self) {
+        for state in &self.functions {
+            state.function.trace(self.heap);
+        }
+
+        self.heap.collect();
+    }
+
+    /// Returns the constant holding the name of an identifier, reusing it
+    /// if the chunk already has one.
+    fn identifier_constant(&mut self, name: &Token) -> CompileResult<usize> {
+        let name = self.copy_string(name.lexeme);
+        if let Some(&constant) = self.state().names.get(&name) {
+            return Ok(constant);
+        }
+
+        let constant = self.make_constant(Value::String(name))?;
+        self.state_mut().names.insert(name, constant);
+
+        Ok(constant)
+    }
+
+    fn end(&mut self) -> Function {
+        self.emit_return();
+        self.functions
+            .pop()
+            .expect("no function being compiled")
+            .function
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> CompileResult<()> {
+        self.advance()?;
+
+        let prefix_rule = match get_rule(self.previous.kind).prefix {
+            Some(rule) => rule,
+            None => return Err(self.error("Expect expression.")),
+        };
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix_rule(self, can_assign)?;
+
+        while precedence <= get_rule(self.current.kind).precedence {
+            self.advance()?;
+            if let Some(infix_rule) = get_rule(self.previous.kind).infix {
+                infix_rule(self, can_assign)?;
+            }
+        }
+
+        if can_assign && self.check(TokenKind::Equal) {
+            return Err(self.error_at_current("Invalid assignment target."));
+        }
+
+        Ok(())
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+
+        loop {
+            let state = self.state();
+            let is_captured = match state.locals.last() {
+                Some(local) if local.depth > Some(state.scope_depth) => local.is_captured,
+                _ => break,
+            };
+
+            if is_captured {
+                self.emit(OpCode::CloseUpvalue);
+            } else {
+                self.emit(OpCode::Pop);
+            }
+            self.state_mut().locals.pop();
+        }
+    }
+
+    fn add_local(&mut self, name: Token<'a>) -> CompileResult<()> {
+        if self.state().locals.len() == MAX_LOCALS {
+            return Err(self.error("Too many local variables in function."));
+        }
+
+        self.state_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+
+        Ok(())
+    }
+
+    /// Resolves `name` to a local slot of the function at `depth` in the stack
+    /// of functions being compiled.
+    fn resolve_local(&self, depth: usize, name: &Token) -> CompileResult<Option<u8>> {
+        for (slot, local) in self.functions[depth].locals.iter().enumerate().rev() {
+            if local.name.lexeme == name.lexeme {
+                if local.depth.is_none() {
+                    return Err(self.error("Can't read local variable in its own initializer."));
+                }
+                return Ok(Some(slot as u8));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Resolves `name` to a variable captured by the function at `depth` from
+    /// one of its enclosing functions.
+    fn resolve_upvalue(&mut self, depth: usize, name: &Token) -> CompileResult<Option<u8>> {
+        if depth == 0 {
+            return Ok(None);
+        }
+
+        let enclosing = depth - 1;
+        if let Some(slot) = self.resolve_local(enclosing, name)? {
+            self.functions[enclosing].locals[slot as usize].is_captured = true;
+            return self.add_upvalue(depth, slot, true).map(Some);
+        }
+        if let Some(index) = self.resolve_upvalue(enclosing, name)? {
+            return self.add_upvalue(depth, index, false).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, depth: usize, index: u8, is_local: bool) -> CompileResult<u8> {
+        let upvalue = UpvalueDescriptor { is_local, index };
+        let upvalues = &self.functions[depth].function.upvalues;
+
+        if let Some(existing) = upvalues.iter().position(|&other| other == upvalue) {
+            return Ok(existing as u8);
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            return Err(self.error("Too many closure variables in function."));
+        }
+
+        let upvalues = &mut self.functions[depth].function.upvalues;
+        upvalues.push(upvalue);
+
+        Ok((upvalues.len() - 1) as u8)
+    }
+
+    fn declare_variable(&mut self) -> CompileResult<()> {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return Ok(());
+        }
+
+        let name = self.previous;
+        for local in state.locals.iter().rev() {
+            if local.depth.is_some_and(|depth| depth < state.scope_depth) {
+                break;
+            }
+            if local.name.lexeme == name.lexeme {
+                return Err(self.error("Already a variable with this name in this scope."));
+            }
+        }
+
+        self.add_local(name)
+    }
+
+    fn parse_variable(&mut self, message: &str) -> CompileResult<usize> {
+        self.consume(TokenKind::Identifier, message)?;
+
+        self.declare_variable()?;
+        if self.state().scope_depth > 0 {
+            return Ok(0);
+        }
+
+        let name = self.previous;
+        self.identifier_constant(&name)
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(state.scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            self.emit_indexed(OpCode::DefineGlobal, global);
+        }
+    }
+
+    fn argument_list(&mut self) -> CompileResult<u8> {
+        let mut arg_count = 0;
+
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                self.expression()?;
+                if arg_count == MAX_ARITY {
+                    return Err(self.error("Can't have more than 255 arguments."));
+                }
+                arg_count += 1;
+
+                if !self.matches(TokenKind::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after arguments.")?;
+
+        Ok(arg_count as u8)
+    }
+
+    /// Compiles a declaration, recovering from any error in it.
+    ///
+    /// An error aborts the whole declaration, so that it is the only one
+    /// reported for it, then the compiler enters panic mode: tokens are skipped
+    /// up to the next statement boundary, where compilation resumes.
+    fn declaration(&mut self) {
+        let state = self.state();
+        let checkpoint = Checkpoint {
+            functions: self.functions.len(),
+            classes: self.classes.len(),
+            locals: state.locals.len(),
+            scope_depth: state.scope_depth,
+        };
+
+        if let Err(diagnostic) = self.try_declaration() {
+            self.diagnostics.push(diagnostic);
+
+            self.functions.truncate(checkpoint.functions);
+            self.classes.truncate(checkpoint.classes);
+            let state = self.state_mut();
+            state.locals.truncate(checkpoint.locals);
+            state.scope_depth = checkpoint.scope_depth;
+
+            self.synchronize();
+        }
+    }
+
+    fn synchronize(&mut self) {
+        while !self.check(TokenKind::Eof) {
+            if self.previous.kind == TokenKind::Semicolon {
+                return;
+            }
+
+            match self.current.kind {
+                TokenKind::Class
+                | TokenKind::Fun
+                | TokenKind::Var
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::Print
+                | TokenKind::Return => return,
+                // Errors in skipped tokens would only be cascading ones.
+                _ => {
+                    let _ = self.advance();
+                }
+            }
+        }
+    }
+
+    fn try_declaration(&mut self) -> CompileResult<()> {
+        if self.matches(TokenKind::Class)? {
+            self.class_declaration()
+        } else if self.matches(TokenKind::Fun)? {
+            self.fun_declaration()
+        } else if self.matches(TokenKind::Var)? {
+            self.var_declaration()
+        } else {
+            self.statement()
+        }
+    }
+
+    fn class_declaration(&mut self) -> CompileResult<()> {
+        self.consume(TokenKind::Identifier, "Expect class name.")?;
+        let class_name = self.previous;
+        let name_constant = self.identifier_constant(&class_name)?;
+        self.declare_variable()?;
+
+        self.emit_indexed(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if self.matches(TokenKind::Less)? {
+            self.consume(TokenKind::Identifier, "Expect superclass name.")?;
+            self.variable(false)?;
+            if class_name.lexeme == self.previous.lexeme {
+                return Err(self.error("A class can't inherit from itself."));
+            }
+
+            // The superclass is stored in a local named `super`, in a scope
+            // wrapping the methods, so that they capture it.
+            self.begin_scope();
+            self.add_local(Token::new(TokenKind::Super, "super", Position::init()))?;
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false)?;
+            self.emit(OpCode::Inherit);
+            self.classes
+                .last_mut()
+                .expect("no class being compiled")
+                .has_superclass = true;
+        }
+
+        // Methods are bound to the class, which is left on the stack while
+        // they are compiled.
+        self.named_variable(&class_name, false)?;
+        self.consume(TokenKind::LeftBrace, "Expect '{' before class body.")?;
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            self.method()?;
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after class body.")?;
+        self.emit(OpCode::Pop);
+
+        let class = self.classes.pop().expect("no class being compiled");
+        if class.has_superclass {
+            self.end_scope();
+        }
+
+        Ok(())
+    }
+
+    fn method(&mut self) -> CompileResult<()> {
+        self.consume(TokenKind::Identifier, "Expect method name.")?;
+        let name = self.previous;
+        let constant = self.identifier_constant(&name)?;
+
+        let kind = if name.lexeme == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        self.function(kind)?;
+        self.emit_indexed(OpCode::Method, constant);
+
+        Ok(())
+    }
+
+    fn fun_declaration(&mut self) -> CompileResult<()> {
+        let global = self.parse_variable("Expect function name.")?;
+
+        self.mark_initialized();
+        self.function(FunctionKind::Function)?;
+        self.define_variable(global);
+
+        Ok(())
+    }
+
+    fn function(&mut self, kind: FunctionKind) -> CompileResult<()> {
+        let name = self.copy_string(self.previous.lexeme);
+        self.functions.push(FunctionState::new(kind, Some(name)));
+        self.begin_scope();
+
+        self.consume(TokenKind::LeftParen, "Expect '(' after function name.")?;
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                if self.state().function.arity == MAX_ARITY {
+                    return Err(self.error_at_current("Can't have more than 255 parameters."));
+                }
+                self.state_mut().function.arity += 1;
+
+                let constant = self.parse_variable("Expect parameter name.")?;
+                self.define_variable(constant);
+
+                if !self.matches(TokenKind::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after parameters.")?;
+        self.consume(TokenKind::LeftBrace, "Expect '{' before function body.")?;
+        self.block()?;
+
+        let function = self.end();
+        let function = self.alloc(function);
+        let constant = self.make_constant(Value::Function(function))?;
+        self.emit_indexed(OpCode::Closure, constant);
+
+        Ok(())
+    }
+
+    fn var_declaration(&mut self) -> CompileResult<()> {
+        let global = self.parse_variable("Expect variable name.")?;
+
+        if self.matches(TokenKind::Equal)? {
+            self.expression()?;
+        } else {
+            self.emit(OpCode::Nil);
+        }
+        self.consume(
+            TokenKind::Semicolon,
+            "Expect ';' after variable declaration.",
+        )?;
+
+        self.define_variable(global);
+
+        Ok(())
+    }
+
+    fn statement(&mut self) -> CompileResult<()> {
+        if self.matches(TokenKind::Print)? {
+            self.print_statement()
+        } else if self.matches(TokenKind::Return)? {
+            self.return_statement()
+        } else if self.matches(TokenKind::For)? {
+            self.for_statement()
+        } else if self.matches(TokenKind::If)? {
+            self.if_statement()
+        } else if self.matches(TokenKind::While)? {
+            self.while_statement()
+        } else if self.matches(TokenKind::LeftBrace)? {
+            self.begin_scope();
+            self.block()?;
+            self.end_scope();
+            Ok(())
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    fn print_statement(&mut self) -> CompileResult<()> {
+        self.expression()?;
+        self.consume(TokenKind::Semicolon, "Expect ';' after value.")?;
+        self.emit(OpCode::Print);
+
+        Ok(())
+    }
+
+    fn return_statement(&mut self) -> CompileResult<()> {
+        if self.state().kind == FunctionKind::Script {
+            return Err(self.error("Can't return from top-level code."));
+        }
+
+        if self.matches(TokenKind::Semicolon)? {
+            self.emit_return();
+        } else {
+            if self.state().kind == FunctionKind::Initializer {
+                // Initializers always return `this`, which they may spell out.
+                let keyword = self.previous;
+                if self.matches(TokenKind::This)? && self.matches(TokenKind::Semicolon)? {
+                    self.emit_return();
+                    return Ok(());
+                }
+
+                return Err(Diagnostic::error(
+                    "Can't return a value from an initializer.",
+                    &keyword,
+                ));
+            }
+
+            self.expression()?;
+            self.consume(TokenKind::Semicolon, "Expect ';' after return value.")?;
+            self.emit(OpCode::Return);
+        }
+
+        Ok(())
+    }
+
+    fn if_statement(&mut self) -> CompileResult<()> {
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'if'.")?;
+        self.expression()?;
+        self.consume(TokenKind::RightParen, "Expect ')' after condition.")?;
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit(OpCode::Pop);
+        self.statement()?;
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(then_jump)?;
+        self.emit(OpCode::Pop);
+
+        if self.matches(TokenKind::Else)? {
+            self.statement()?;
+        }
+        self.patch_jump(else_jump)
+    }
+
+    fn while_statement(&mut self) -> CompileResult<()> {
+        let loop_start = self.chunk().code.len();
+
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'while'.")?;
+        self.expression()?;
+        self.consume(TokenKind::RightParen, "Expect ')' after condition.")?;
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit(OpCode::Pop);
+        self.statement()?;
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit(OpCode::Pop);
+
+        Ok(())
+    }
+
+    fn for_statement(&mut self) -> CompileResult<()> {
+        self.begin_scope();
+
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.")?;
+        if self.matches(TokenKind::Semicolon)? {
+            // No initializer.
+        } else if self.matches(TokenKind::Var)? {
+            self.var_declaration()?;
+        } else {
+            self.expression_statement()?;
+        }
+
+        let mut loop_start = self.chunk().code.len();
+
+        let mut exit_jump = None;
+        if !self.matches(TokenKind::Semicolon)? {
+            self.expression()?;
+            self.consume(TokenKind::Semicolon, "Expect ';' after loop condition.")?;
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit(OpCode::Pop);
+        }
+
+        if !self.matches(TokenKind::RightParen)? {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.chunk().code.len();
+
+            self.expression()?;
+            self.emit(OpCode::Pop);
+            self.consume(TokenKind::RightParen, "Expect ')' after for clauses.")?;
+
+            self.emit_loop(loop_start)?;
+            loop_start = increment_start;
+            self.patch_jump(body_jump)?;
+        }
+
+        self.statement()?;
+        self.emit_loop(loop_start)?;
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump)?;
+            self.emit(OpCode::Pop);
+        }
+
+        self.end_scope();
+
+        Ok(())
+    }
+
+    fn expression_statement(&mut self) -> CompileResult<()> {
+        self.expression()?;
+        self.consume(TokenKind::Semicolon, "Expect ';' after expression.")?;
+        self.emit(OpCode::Pop);
+
+        Ok(())
+    }
+
+    fn block(&mut self) -> CompileResult<()> {
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            self.declaration();
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after block.")
+    }
+
+    fn expression(&mut self) -> CompileResult<()> {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn variable(&mut self, can_assign: bool) -> CompileResult<()> {
+        let name = self.previous;
+        self.named_variable(&name, can_assign)
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) -> CompileResult<()> {
+        let depth = self.functions.len() - 1;
+
+        let (get_op, set_op, operand) = if let Some(slot) = self.resolve_local(depth, name)? {
+            (OpCode::GetLocal, OpCode::SetLocal, slot as usize)
+        } else if let Some(index) = self.resolve_upvalue(depth, name)? {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, index as usize)
+        } else {
+            let global = self.identifier_constant(name)?;
+            (OpCode::GetGlobal, OpCode::SetGlobal, global)
+        };
+
+        if can_assign && self.matches(TokenKind::Equal)? {
+            self.expression()?;
+            self.emit_indexed_at(set_op, operand, name.position);
+        } else {
+            self.emit_indexed(get_op, operand);
+        }
+
+        Ok(())
+    }
+
+    fn number(&mut self, _can_assign: bool) -> CompileResult<()> {
+        match self.previous.lexeme.parse() {
+            Ok(value) => self.emit_constant(Value::Number(value)),
+            Err(_) => Err(self.error("Invalid number literal.")),
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) -> CompileResult<()> {
+        let lexeme = self.previous.lexeme;
+        let value = Value::String(self.copy_string(&lexeme[1..lexeme.len() - 1]));
+
+        self.emit_constant(value)
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> CompileResult<()> {
+        match self.previous.kind {
+            TokenKind::False => self.emit(OpCode::False),
+            TokenKind::Nil => self.emit(OpCode::Nil),
+            TokenKind::True => self.emit(OpCode::True),
+            kind => unreachable!("literal {:?}", kind),
+        }
+
+        Ok(())
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> CompileResult<()> {
+        self.expression()?;
+        self.consume(TokenKind::RightParen, "Expect ')' after expression.")
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> CompileResult<()> {
+        let operator = self.previous;
+
+        self.parse_precedence(Precedence::Unary)?;
+
+        let op_code = match operator.kind {
+            TokenKind::Bang => OpCode::Not,
+            TokenKind::Minus => OpCode::Negate,
+            kind => unreachable!("unary operator {:?}", kind),
+        };
+        self.emit_byte_at(op_code as u8, operator.position);
+
+        Ok(())
+    }
+
+    fn call(&mut self, _can_assign: bool) -> CompileResult<()> {
+        let paren = self.previous.position;
+        let arg_count = self.argument_list()?;
+        self.emit_byte_at(OpCode::Call as u8, paren);
+        self.emit_byte_at(arg_count, paren);
+
+        Ok(())
+    }
+
+    fn dot(&mut self, can_assign: bool) -> CompileResult<()> {
+        self.consume(TokenKind::Identifier, "Expect property name after '.'.")?;
+        let token = self.previous;
+        let name = self.identifier_constant(&token)?;
+
+        if can_assign && self.matches(TokenKind::Equal)? {
+            self.expression()?;
+            self.emit_indexed_at(OpCode::SetProperty, name, token.position);
+        } else if self.matches(TokenKind::LeftParen)? {
+            let arg_count = self.argument_list()?;
+            self.emit_indexed_at(OpCode::Invoke, name, token.position);
+            self.emit_byte_at(arg_count, token.position);
+        } else {
+            self.emit_indexed(OpCode::GetProperty, name);
+        }
+
+        Ok(())
+    }
+
+    fn super_(&mut self, _can_assign: bool) -> CompileResult<()> {
+        match self.classes.last() {
+            None => return Err(self.error("Can't use 'super' outside of a class.")),
+            Some(class) if !class.has_superclass => {
+                return Err(self.error("Can't use 'super' in a class with no superclass."))
+            }
+            Some(_) => {}
+        }
+
+        self.consume(TokenKind::Dot, "Expect '.' after 'super'.")?;
+        self.consume(TokenKind::Identifier, "Expect superclass method name.")?;
+        let name = self.previous;
+        let name = self.identifier_constant(&name)?;
+
+        let this = Token::new(TokenKind::This, "this", Position::init());
+        let super_ = Token::new(TokenKind::Super, "super", Position::init());
+
+        self.named_variable(&this, false)?;
+        if self.matches(TokenKind::LeftParen)? {
+            let arg_count = self.argument_list()?;
+            self.named_variable(&super_, false)?;
+            self.emit_indexed(OpCode::SuperInvoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(&super_, false)?;
+            self.emit_indexed(OpCode::GetSuper, name);
+        }
+
+        Ok(())
+    }
+
+    fn this(&mut self, _can_assign: bool) -> CompileResult<()> {
+        if self.classes.is_empty() {
+            return Err(self.error("Can't use 'this' outside of a class."));
+        }
+
+        self.variable(false)
+    }
+
+    fn and(&mut self, _can_assign: bool) -> CompileResult<()> {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit(OpCode::Pop);
+        self.parse_precedence(Precedence::And)?;
+
+        self.patch_jump(end_jump)
+    }
+
+    fn or(&mut self, _can_assign: bool) -> CompileResult<()> {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump)?;
+        self.emit(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Or)?;
+        self.patch_jump(end_jump)
+    }
+
+    fn binary(&mut self, _can_assign: bool) -> CompileResult<()> {
+        let operator = self.previous;
+
+        self.parse_precedence(get_rule(operator.kind).precedence.next())?;
+
+        let op_codes: &[OpCode] = match operator.kind {
+            TokenKind::BangEqual => &[OpCode::Equal, OpCode::Not],
+            TokenKind::EqualEqual => &[OpCode::Equal],
+            TokenKind::Greater => &[OpCode::Greater],
+            TokenKind::GreaterEqual => &[OpCode::Less, OpCode::Not],
+            TokenKind::Less => &[OpCode::Less],
+            TokenKind::LessEqual => &[OpCode::Greater, OpCode::Not],
+            TokenKind::Plus => &[OpCode::Add],
+            TokenKind::Minus => &[OpCode::Substract],
+            TokenKind::Star => &[OpCode::Multiply],
+            TokenKind::Slash => &[OpCode::Divide],
+            kind => unreachable!("binary operator {:?}", kind),
+        };
+        for &op_code in op_codes {
+            self.emit_byte_at(op_code as u8, operator.position);
+        }
+
+        Ok(())
+    }
+
+    fn error(&self, message: &str) -> Diagnostic {
+        Diagnostic::error(message, &self.previous)
+    }
+
+    fn error_at_current(&self, message: &str) -> Diagnostic {
+        Diagnostic::error(message, &self.current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Severity;
+
+    fn compile_test(source: &str) -> Result<Chunk, InterpretError> {
+        compile(source, &mut Heap::new())
+    }
+
+    #[test]
+    fn arithmetic() {
+        let chunk = compile_test("-(1 + 2) * 3 / 4;").unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Add as u8,
+                OpCode::Negate as u8,
+                OpCode::Constant as u8,
+                2,
+                OpCode::Multiply as u8,
+                OpCode::Constant as u8,
+                3,
+                OpCode::Divide as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+        assert_eq!(
+            chunk.constants,
+            vec![
+                Value::Number(1.0),
+                Value::Number(2.0),
+                Value::Number(3.0),
+                Value::Number(4.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        let chunk = compile_test("1 - 2 - 3 * 4;").unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Substract as u8,
+                OpCode::Constant as u8,
+                2,
+                OpCode::Constant as u8,
+                3,
+                OpCode::Multiply as u8,
+                OpCode::Substract as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn comparison() {
+        let chunk = compile_test("!(1 >= 2) != nil;").unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Less as u8,
+                OpCode::Not as u8,
+                OpCode::Not as u8,
+                OpCode::Nil as u8,
+                OpCode::Equal as u8,
+                OpCode::Not as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn string() {
+        let mut heap = Heap::new();
+        let chunk = compile(r#""lox" + "rs";"#, &mut heap).unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Add as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+        assert_eq!(chunk.constants[0].to_string(), "lox");
+        assert_eq!(chunk.constants[1].to_string(), "rs");
+    }
+
+    #[test]
+    fn long_constants() {
+        let source: String = (0..300).map(|i| format!("{};", i)).collect();
+        let chunk = compile_test(&source).unwrap();
+
+        assert_eq!(chunk.constants.len(), 300);
+        assert_eq!(
+            chunk.code[..4],
+            [
+                OpCode::Constant as u8,
+                0,
+                OpCode::Pop as u8,
+                OpCode::Constant as u8
+            ]
+        );
+        assert_eq!(
+            chunk.code[256 * 3..256 * 3 + 5],
+            [OpCode::ConstantLong as u8, 0, 1, 0, OpCode::Pop as u8]
+        );
+    }
+
+    #[test]
+    fn long_names() {
+        let mut source: String = (0..300).map(|i| format!("{};", i)).collect();
+        source.push_str("var a = 1; print a;");
+        let chunk = compile_test(&source).unwrap();
+
+        assert_eq!(chunk.constants.len(), 302);
+        let start = 256 * 3 + 44 * 5;
+        assert_eq!(
+            chunk.code[start..start + 13],
+            [
+                OpCode::ConstantLong as u8,
+                0,
+                1,
+                45,
+                OpCode::DefineGlobalLong as u8,
+                0,
+                1,
+                44,
+                OpCode::GetGlobalLong as u8,
+                0,
+                1,
+                44,
+                OpCode::Print as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn positions() {
+        let chunk = compile_test("var a = 1;\nprint -a\n  + 2;").unwrap();
+
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while let Some(op_code) = chunk.code_at(offset) {
+            instructions.push((op_code, chunk.position_at(offset).unwrap()));
+            offset += op_code.width();
+        }
+
+        assert_eq!(
+            instructions,
+            vec![
+                (OpCode::Constant, Position::new(1, 9)),
+                (OpCode::DefineGlobal, Position::new(1, 10)),
+                (OpCode::GetGlobal, Position::new(2, 8)),
+                (OpCode::Negate, Position::new(2, 7)),
+                (OpCode::Constant, Position::new(3, 5)),
+                (OpCode::Add, Position::new(3, 3)),
+                (OpCode::Print, Position::new(3, 6)),
+                (OpCode::Nil, Position::new(3, 6)),
+                (OpCode::Return, Position::new(3, 6)),
+            ]
+        );
+    }
+
+    #[test]
+    fn globals() {
+        let chunk = compile_test("var a = 1; var b; b = a;").unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Constant as u8,
+                1,
+                OpCode::DefineGlobal as u8,
+                0,
+                OpCode::Nil as u8,
+                OpCode::DefineGlobal as u8,
+                2,
+                OpCode::GetGlobal as u8,
+                0,
+                OpCode::SetGlobal as u8,
+                2,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn locals() {
+        let chunk = compile_test("{ var a = 1; { var b = a; var c; } }").unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::GetLocal as u8,
+                1,
+                OpCode::Nil as u8,
+                OpCode::Pop as u8,
+                OpCode::Pop as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn local_errors() {
+        assert!(compile_test("{ var a = a; }").is_err());
+        assert!(compile_test("{ var a = 1; var a = 2; }").is_err());
+        assert!(compile_test("{ var a = 1; { var a = a; } }").is_err());
+        assert!(compile_test("{ var a = 1; { var a = 2; } }").is_ok());
+        assert!(compile_test("var a = 1; var a = a;").is_ok());
+    }
+
+    #[test]
+    fn if_else() {
+        let chunk = compile_test("if (true) print 1; else print 2;").unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::True as u8,
+                OpCode::JumpIfFalse as u8,
+                0,
+                7,
+                OpCode::Pop as u8,
+                OpCode::Constant as u8,
+                0,
+                OpCode::Print as u8,
+                OpCode::Jump as u8,
+                0,
+                4,
+                OpCode::Pop as u8,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Print as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn while_loop() {
+        let chunk = compile_test("while (false) print 1;").unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::False as u8,
+                OpCode::JumpIfFalse as u8,
+                0,
+                7,
+                OpCode::Pop as u8,
+                OpCode::Constant as u8,
+                0,
+                OpCode::Print as u8,
+                OpCode::Loop as u8,
+                0,
+                11,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn jump_too_large() {
+        let body = "1;".repeat(u16::MAX as usize / 2 + 1);
+
+        assert!(compile_test(&format!("if (true) {{ {} }}", body)).is_err());
+        assert!(compile_test(&format!("while (true) {{ {} }}", body)).is_err());
+    }
+
+    #[test]
+    fn function() {
+        let mut heap = Heap::new();
+        let chunk = compile(
+            "fun add(a, b) { return a + b; } print add(1, 2);",
+            &mut heap,
+        )
+        .unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Closure as u8,
+                1,
+                OpCode::DefineGlobal as u8,
+                0,
+                OpCode::GetGlobal as u8,
+                0,
+                OpCode::Constant as u8,
+                2,
+                OpCode::Constant as u8,
+                3,
+                OpCode::Call as u8,
+                2,
+                OpCode::Print as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+
+        let function = match chunk.constants[1] {
+            Value::Function(function) => function,
+            value => panic!("expected a function, got {:?}", value),
+        };
+        assert_eq!(function.arity, 2);
+        assert_eq!(function.to_string(), "<fn add>");
+        assert_eq!(
+            function.chunk.code,
+            vec![
+                OpCode::GetLocal as u8,
+                1,
+                OpCode::GetLocal as u8,
+                2,
+                OpCode::Add as u8,
+                OpCode::Return as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn closure() {
+        let mut heap = Heap::new();
+        let source = "fun outer() { var x = 1; fun middle() { fun inner() { return x; } } }";
+        let chunk = compile(source, &mut heap).unwrap();
+
+        let function = |value: Value| match value {
+            Value::Function(function) => function,
+            value => panic!("expected a function, got {:?}", value),
+        };
+        let outer = function(chunk.constants[1]);
+        let middle = function(outer.chunk.constants[1]);
+        let inner = function(middle.chunk.constants[0]);
+
+        assert_eq!(
+            outer.chunk.code,
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::Closure as u8,
+                1,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+        assert!(outer.upvalues.is_empty());
+        assert_eq!(
+            middle.upvalues,
+            vec![UpvalueDescriptor {
+                is_local: true,
+                index: 1
+            }]
+        );
+        assert_eq!(
+            inner.upvalues,
+            vec![UpvalueDescriptor {
+                is_local: false,
+                index: 0
+            }]
+        );
+        assert_eq!(inner.chunk.code[..2], [OpCode::GetUpvalue as u8, 0]);
+    }
+
+    #[test]
+    fn captured_local() {
+        let chunk = compile_test("{ var a = 1; fun f() { return a; } }").unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::Closure as u8,
+                1,
+                OpCode::Pop as u8,
+                OpCode::CloseUpvalue as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn class() {
+        let mut heap = Heap::new();
+        let source = "class A { init(x) { this.x = x; } get() { return this.x; } }";
+        let chunk = compile(source, &mut heap).unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Class as u8,
+                0,
+                OpCode::DefineGlobal as u8,
+                0,
+                OpCode::GetGlobal as u8,
+                0,
+                OpCode::Closure as u8,
+                2,
+                OpCode::Method as u8,
+                1,
+                OpCode::Closure as u8,
+                4,
+                OpCode::Method as u8,
+                3,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+
+        let init = match chunk.constants[2] {
+            Value::Function(function) => function,
+            value => panic!("expected a function, got {:?}", value),
+        };
+        assert_eq!(
+            init.chunk.code,
+            vec![
+                OpCode::GetLocal as u8,
+                0,
+                OpCode::GetLocal as u8,
+                1,
+                OpCode::SetProperty as u8,
+                0,
+                OpCode::Pop as u8,
+                OpCode::GetLocal as u8,
+                0,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn class_errors() {
+        assert!(compile_test("class A { init() { return 1; } }").is_err());
+        assert!(compile_test("class A { init() { return; } }").is_ok());
+        assert!(compile_test("class A { init() { return this; } }").is_ok());
+        assert!(compile_test("class A { init() { return this.a; } }").is_err());
+        assert!(compile_test("class A { init() { return this == nil; } }").is_err());
+        assert!(compile_test("print this;").is_err());
+        assert!(compile_test("fun f() { return this; }").is_err());
+        assert!(compile_test("class A { f() { fun g() { return this; } } }").is_ok());
+        assert!(compile_test("var a; a.b = 1 = 2;").is_err());
+    }
+
+    #[test]
+    fn inheritance() {
+        let mut heap = Heap::new();
+        let source = "class A {} class B < A { f() { return super.f(1); } }";
+        let chunk = compile(source, &mut heap).unwrap();
+
+        assert_eq!(
+            chunk.code[7..],
+            [
+                OpCode::Class as u8,
+                1,
+                OpCode::DefineGlobal as u8,
+                1,
+                OpCode::GetGlobal as u8,
+                0,
+                OpCode::GetGlobal as u8,
+                1,
+                OpCode::Inherit as u8,
+                OpCode::GetGlobal as u8,
+                1,
+                OpCode::Closure as u8,
+                3,
+                OpCode::Method as u8,
+                2,
+                OpCode::Pop as u8,
+                OpCode::CloseUpvalue as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+
+        let method = match chunk.constants[3] {
+            Value::Function(function) => function,
+            value => panic!("expected a function, got {:?}", value),
+        };
+        assert_eq!(
+            method.chunk.code,
+            vec![
+                OpCode::GetLocal as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::GetUpvalue as u8,
+                0,
+                OpCode::SuperInvoke as u8,
+                0,
+                1,
+                OpCode::Return as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn invoke() {
+        let chunk = compile_test("var a; a.f(1, 2); a.g;").unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Nil as u8,
+                OpCode::DefineGlobal as u8,
+                0,
+                OpCode::GetGlobal as u8,
+                0,
+                OpCode::Constant as u8,
+                2,
+                OpCode::Constant as u8,
+                3,
+                OpCode::Invoke as u8,
+                1,
+                2,
+                OpCode::Pop as u8,
+                OpCode::GetGlobal as u8,
+                0,
+                OpCode::GetProperty as u8,
+                4,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        assert!(compile_test("class A < A {}").is_err());
+        assert!(compile_test("{ class A < A {} }").is_err());
+        assert!(compile_test("class A { f() { super.f(); } }").is_err());
+        assert!(compile_test("fun f() { super.f(); }").is_err());
+        assert!(compile_test("class A {} class B < A { f() { super; } }").is_err());
+    }
+
+    #[test]
+    fn diagnostics() {
+        let diagnostics = match compile_test("var a = 1;\nprint a +;") {
+            Err(InterpretError::CompileError(diagnostics)) => diagnostics,
+            result => panic!("expected a compile error, got {:?}", result),
+        };
+
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                message: String::from("Expect expression."),
+                start: Position::new(2, 10),
+                end: Position::new(2, 11),
+                severity: Severity::Error,
+            }]
+        );
+
+        let diagnostics = match compile_test("print 1") {
+            Err(InterpretError::CompileError(diagnostics)) => diagnostics,
+            result => panic!("expected a compile error, got {:?}", result),
+        };
+        assert_eq!(diagnostics[0].start, Position::new(1, 8));
+    }
+
+    #[test]
+    fn error_recovery() {
+        let source = "print 1 +;\nvar = 2;\nprint 3;\nfun f(a b) {}\nfun g() { print 1 2; }\n{ print @; }\nprint 4";
+        let diagnostics = match compile_test(source) {
+            Err(InterpretError::CompileError(diagnostics)) => diagnostics,
+            result => panic!("expected a compile error, got {:?}", result),
+        };
+
+        let errors: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.start.line, diagnostic.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (1, "Expect expression."),
+                (2, "Expect variable name."),
+                (4, "Expect ')' after parameters."),
+                (5, "Expect ';' after value."),
+                (6, "Unexpected character."),
+                (7, "Expect ';' after value."),
+            ]
+        );
+    }
+
+    #[test]
+    fn syntax_error() {
+        assert!(compile_test("1 +;").is_err());
+        assert!(compile_test("(1 + 2;").is_err());
+        assert!(compile_test("1 2;").is_err());
+        assert!(compile_test("print 1").is_err());
+        assert!(compile_test("return 1;").is_err());
+        assert!(compile_test("fun f(a b) {}").is_err());
+        assert!(compile_test("f(1 2);").is_err());
+        assert!(compile_test("var 1 = 2;").is_err());
+        assert!(compile_test("var a = 1; a + 1 = 2;").is_err());
     }
 }
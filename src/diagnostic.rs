@@ -0,0 +1,131 @@
+use std::fmt;
+
+use crate::lexer::{Position, Token};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Severity {
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found in the source code, spanning from `start` up to, but
+/// excluding, `end`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub start: Position,
+    pub end: Position,
+    pub severity: Severity,
+}
+
+impl Diagnostic {
+    pub fn error(message: &str, token: &Token) -> Self {
+        Diagnostic {
+            message: message.to_string(),
+            start: token.position,
+            end: token.end(),
+            severity: Severity::Error,
+        }
+    }
+
+    /// Renders the diagnostic along with the source line it points at, with
+    /// its span underlined by carets.
+    pub fn render(&self, source: &str) -> String {
+        let mut rendered = format!(
+            "{}: {}\n --> {}:{}\n",
+            self.severity, self.message, self.start.line, self.start.column
+        );
+
+        let line = match source.lines().nth(self.start.line - 1) {
+            Some(line) => line,
+            None => return rendered,
+        };
+
+        // Spans running over several lines are underlined up to the end of
+        // their first line, and empty spans still get a caret.
+        let line_length = line.chars().count() + 1;
+        let start = self.start.column.min(line_length);
+        let end = if self.end.line == self.start.line {
+            self.end.column.min(line_length)
+        } else {
+            line_length
+        };
+        let width = end.saturating_sub(start).max(1);
+
+        let gutter = self.start.line.to_string().len();
+        rendered.push_str(&format!("{:gutter$} |\n", "", gutter = gutter));
+        rendered.push_str(&format!("{} | {}\n", self.start.line, line));
+        // Columns can exceed the widths `format!` supports on long lines.
+        rendered.push_str(&format!(
+            "{:gutter$} | {}{}",
+            "",
+            " ".repeat(start - 1),
+            "^".repeat(width),
+            gutter = gutter
+        ));
+
+        rendered
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[line {}:{}] {}: {}",
+            self.start.line, self.start.column, self.severity, self.message
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::TokenKind;
+
+    #[test]
+    fn render() {
+        let source = "var a = 1;\nprint a +;\n";
+        let token = Token::new(TokenKind::Identifier, "a +", Position::new(2, 7));
+        let diagnostic = Diagnostic::error("Expect expression.", &token);
+
+        assert_eq!(
+            diagnostic.to_string(),
+            "[line 2:7] error: Expect expression."
+        );
+        assert_eq!(
+            diagnostic.render(source),
+            "error: Expect expression.\n --> 2:7\n  |\n2 | print a +;\n  |       ^^^"
+        );
+    }
+
+    #[test]
+    fn render_wide_line() {
+        let source = format!("{}print -nil;", " ".repeat(70_000));
+        let token = Token::new(TokenKind::Minus, "-", Position::new(1, 70_007));
+        let diagnostic = Diagnostic::error("Operand must be a number.", &token);
+
+        let rendered = diagnostic.render(&source);
+        assert!(rendered.starts_with("error: Operand must be a number.\n --> 1:70007\n"));
+        assert!(rendered.ends_with(&format!("  | {}^", " ".repeat(70_006))));
+    }
+
+    #[test]
+    fn render_at_end() {
+        let source = "print 1";
+        let token = Token::new(TokenKind::Eof, "", Position::new(1, 8));
+        let diagnostic = Diagnostic::error("Expect ';' after value.", &token);
+
+        assert_eq!(
+            diagnostic.render(source),
+            "error: Expect ';' after value.\n --> 1:8\n  |\n1 | print 1\n  |        ^"
+        );
+    }
+}
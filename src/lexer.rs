@@ -219,9 +219,9 @@ impl<'a> Scanner<'a> {
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub struct Token<'a> {
-    kind: TokenKind,
-    lexeme: &'a str,
-    position: Position,
+    pub kind: TokenKind,
+    pub lexeme: &'a str,
+    pub position: Position,
 }
 
 impl<'a> Token<'a> {
@@ -232,6 +232,20 @@ impl<'a> Token<'a> {
             position,
         }
     }
+
+    /// Returns the position right after the last character of the token.
+    pub fn end(&self) -> Position {
+        let mut end = self.position;
+        for ch in self.lexeme.chars() {
+            if ch == '\n' {
+                end.next_line();
+            } else {
+                end.next_column();
+            }
+        }
+
+        end
+    }
 }
 
 impl fmt::Display for Token<'_> {
@@ -292,6 +306,7 @@ pub enum TokenKind {
     Comment,
     UnterminatedStringError,
     UnexpectedCharacterError,
+    Eof,
 }
 
 /// A position in the source file (line:column)
@@ -328,10 +343,7 @@ impl Default for Position {
 
 impl PartialOrd for Position {
     fn partial_cmp(&self, other: &Position) -> Option<Ordering> {
-        match self.line.cmp(&other.line) {
-            Ordering::Equal => Some(self.column.cmp(&other.column)),
-            ord => Some(ord),
-        }
+        Some(self.cmp(other))
     }
 }
 
@@ -366,6 +378,15 @@ mod tests {
         assert_eq!(position, Position::new(2, 3));
     }
 
+    #[test]
+    fn token_end() {
+        let token = Token::new(TokenKind::Identifier, "lox", Position::new(2, 3));
+        assert_eq!(token.end(), Position::new(2, 6));
+
+        let token = Token::new(TokenKind::String, "\"a\nbc\"", Position::new(1, 5));
+        assert_eq!(token.end(), Position::new(2, 4));
+    }
+
     #[test]
     fn number() {
         let source = "42 13.37";
@@ -1,4 +1,144 @@
 pub mod bytecode;
 pub mod compiler;
+pub mod diagnostic;
 pub mod lexer;
+pub mod memory;
+pub mod native;
+pub mod object;
+pub mod verifier;
 pub mod vm;
+
+use std::io::{self, Write};
+
+use crate::{
+    bytecode::Chunk,
+    compiler::compile,
+    memory::Heap,
+    vm::{InterpretError, InterpretResult, Vm},
+};
+
+/// Settings of an interpreter run.
+#[derive(Clone, Default, Debug)]
+pub struct Options {
+    /// Collect garbage before every allocation, to flush out rooting bugs.
+    pub stress_gc: bool,
+    /// Trace the execution of every instruction to stderr.
+    pub trace: bool,
+    /// Only trace the function with this name, see `Vm::set_trace_function`.
+    pub trace_function: Option<String>,
+}
+
+pub fn interpret(source: &str) -> InterpretResult {
+    interpret_with_options(source, &Options::default())
+}
+
+pub fn interpret_with_options(source: &str, options: &Options) -> InterpretResult {
+    let mut heap = Heap::new();
+    heap.set_stress(options.stress_gc);
+    let chunk = compile(source, &mut heap)?;
+    let mut vm = Vm::init(chunk, heap)?;
+    if options.trace {
+        vm.set_trace(io::stderr());
+    }
+    if let Some(function) = &options.trace_function {
+        vm.set_trace_function(function);
+    }
+
+    vm.interpret()
+}
+
+/// Compiles `source`, and returns the listing of the script and of every
+/// function it declares, as text or as a JSON array.
+pub fn disassemble(source: &str, json: bool) -> Result<String, InterpretError> {
+    let mut heap = Heap::new();
+    let chunk = compile(source, &mut heap)?;
+
+    let mut listing = Vec::new();
+    write_listing(&chunk, json, &mut listing).expect("writing to a vector cannot fail");
+
+    Ok(String::from_utf8(listing).expect("listings are valid UTF-8"))
+}
+
+fn write_listing<W: Write>(chunk: &Chunk, json: bool, out: &mut W) -> io::Result<()> {
+    let script = "<script>";
+    let functions = chunk.functions();
+
+    if json {
+        write!(out, "[")?;
+        chunk.disassemble_json(script, out)?;
+        for function in functions {
+            write!(out, ",")?;
+            function
+                .chunk
+                .disassemble_json(&function.to_string(), out)?;
+        }
+        writeln!(out, "]")
+    } else {
+        chunk.disassemble(script, out)?;
+        for function in functions {
+            writeln!(out)?;
+            function.chunk.disassemble(&function.to_string(), out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::InterpretError;
+
+    #[test]
+    fn interpret_expression() {
+        assert!(interpret("(1 + 2) * -3;").is_ok());
+        assert!(interpret(r#""con" + "cat";"#).is_ok());
+        assert!(matches!(
+            interpret("1 +;"),
+            Err(InterpretError::CompileError(_))
+        ));
+    }
+
+    #[test]
+    fn stress_gc() {
+        let options = Options {
+            stress_gc: true,
+            ..Options::default()
+        };
+        let source = r#"
+            fun makeCounter() {
+                var count = 0;
+                fun counter() {
+                    count = count + 1;
+                    return "count" + " " + "is";
+                }
+                return counter;
+            }
+            var counter = makeCounter();
+            for (var i = 0; i < 10; i = i + 1) {
+                counter();
+            }
+        "#;
+
+        assert!(interpret_with_options(source, &options).is_ok());
+    }
+
+    #[test]
+    fn disassemble_functions() {
+        let source = "fun f() { fun g() {} }";
+
+        let listing = disassemble(source, false).unwrap();
+        let headers: Vec<_> = listing
+            .lines()
+            .filter(|line| line.starts_with("=="))
+            .collect();
+        assert_eq!(
+            headers,
+            vec!["== <script> ==", "== <fn f> ==", "== <fn g> =="]
+        );
+
+        let listing = disassemble(source, true).unwrap();
+        assert!(listing.starts_with(r#"[{"name":"<script>","code":["#));
+        assert!(listing.ends_with("]}]\n"));
+        assert!(disassemble("fun", false).is_err());
+    }
+}
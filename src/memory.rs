@@ -0,0 +1,367 @@
+use std::{
+    borrow::Borrow,
+    cell::Cell,
+    collections::HashSet,
+    fmt,
+    hash::{Hash, Hasher},
+    mem,
+    ops::Deref,
+    ptr::NonNull,
+};
+
+use crate::{bytecode::Value, object::LoxString};
+
+/// Factor by which the heap may grow after a collection before the next one.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Number of bytes allocated before the first collection.
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+/// An object that can live on a `Heap` and reference other heap objects.
+pub trait Trace {
+    /// Marks every heap object directly referenced by this one.
+    fn trace(&self, heap: &mut Heap);
+
+    /// Number of bytes owned by the object outside of its heap allocation.
+    ///
+    /// Objects whose size changes after allocation must be mutated through
+    /// `Heap::update`, so that the heap keeps track of it.
+    fn size(&self) -> usize {
+        0
+    }
+}
+
+struct GcBox<T: ?Sized> {
+    marked: Cell<bool>,
+    value: T,
+}
+
+/// A handle to an object allocated on a `Heap`.
+///
+/// Handles are plain pointers: they are `Copy`, and only stay valid while
+/// their heap is alive and, whenever it collects garbage, the object is
+/// reachable from the roots of the heap's owner. Handles never leave this
+/// crate: natives see values as `NativeValue`s, which can't outlive their call.
+pub struct Gc<T> {
+    ptr: NonNull<GcBox<T>>,
+}
+
+impl<T> Gc<T> {
+    pub fn ptr_eq(this: &Gc<T>, other: &Gc<T>) -> bool {
+        this.ptr == other.ptr
+    }
+
+    /// Borrows the object for as long as the caller needs.
+    ///
+    /// # Safety
+    ///
+    /// The object must stay alive for all of `'a`.
+    pub(crate) unsafe fn as_ref<'a>(self) -> &'a T {
+        &self.ptr.as_ref().value
+    }
+
+    fn is_marked(&self) -> bool {
+        // SAFETY: see `Deref`.
+        unsafe { self.ptr.as_ref() }.marked.get()
+    }
+}
+
+impl<T> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Gc<T> {}
+
+impl<T> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the object is owned by the heap, which only frees objects
+        // that are no longer reachable from any root.
+        unsafe { &self.ptr.as_ref().value }
+    }
+}
+
+/// Handles compare by identity: two handles are equal when they point to the
+/// same object.
+impl<T> PartialEq for Gc<T> {
+    fn eq(&self, other: &Gc<T>) -> bool {
+        Gc::ptr_eq(self, other)
+    }
+}
+
+impl<T> Eq for Gc<T> {}
+
+impl<T> Hash for Gc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr.hash(state);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// Entry of the string interning table, hashed and compared by contents.
+#[derive(Debug)]
+struct Interned(Gc<LoxString>);
+
+impl PartialEq for Interned {
+    fn eq(&self, other: &Interned) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Interned {}
+
+impl Hash for Interned {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_str().hash(state);
+    }
+}
+
+impl Borrow<str> for Interned {
+    fn borrow(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+/// Owner of every object created by the compiler and the virtual machine.
+///
+/// Strings are interned: all strings with the same contents share a single
+/// allocation, so they can be compared by identity.
+///
+/// The heap is garbage collected with a tracing mark-and-sweep collector. The
+/// heap does not know its roots: its owner checks `should_collect` before
+/// allocating, marks the roots it holds, then calls `collect` to free every
+/// object that was not reached.
+#[derive(Debug)]
+pub struct Heap {
+    objects: Vec<NonNull<GcBox<dyn Trace>>>,
+    strings: HashSet<Interned>,
+    gray_stack: Vec<NonNull<GcBox<dyn Trace>>>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    stress: bool,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            strings: HashSet::new(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            stress: false,
+        }
+    }
+
+    /// In stress mode, `should_collect` always holds, so that owners collect
+    /// garbage on every allocation to flush out rooting bugs.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub(crate) fn alloc<T: Trace + 'static>(&mut self, value: T) -> Gc<T> {
+        let size = mem::size_of::<GcBox<T>>() + value.size();
+        let boxed = Box::new(GcBox {
+            marked: Cell::new(false),
+            value,
+        });
+        let ptr = NonNull::from(Box::leak(boxed));
+
+        self.objects.push(ptr);
+        self.bytes_allocated += size;
+
+        Gc { ptr }
+    }
+
+    /// Returns the interned string with the given contents, allocating a copy
+    /// if there is none yet.
+    pub(crate) fn copy_string(&mut self, value: &str) -> Gc<LoxString> {
+        match self.strings.get(value) {
+            Some(interned) => interned.0,
+            None => self.intern(value.to_string()),
+        }
+    }
+
+    /// Like `copy_string`, but takes ownership of an already built string.
+    pub(crate) fn take_string(&mut self, value: String) -> Gc<LoxString> {
+        match self.strings.get(value.as_str()) {
+            Some(interned) => interned.0,
+            None => self.intern(value),
+        }
+    }
+
+    fn intern(&mut self, value: String) -> Gc<LoxString> {
+        let string = self.alloc(LoxString::new(value));
+        self.strings.insert(Interned(string));
+
+        string
+    }
+
+    /// Runs `update` on `object`, and accounts for how much it grew or shrank.
+    pub(crate) fn update<T: Trace + 'static, R>(
+        &mut self,
+        object: Gc<T>,
+        update: impl FnOnce(&T) -> R,
+    ) -> R {
+        let size = object.size();
+        let result = update(&object);
+        self.bytes_allocated = self.bytes_allocated - size + object.size();
+
+        result
+    }
+
+    pub(crate) fn mark<T: Trace + 'static>(&mut self, object: Gc<T>) {
+        // SAFETY: see `Gc::deref`.
+        let header = unsafe { object.ptr.as_ref() };
+        if header.marked.replace(true) {
+            return;
+        }
+
+        self.gray_stack.push(object.ptr);
+    }
+
+    pub(crate) fn mark_value(&mut self, value: Value) {
+        match value {
+            Value::Bool(_) | Value::Nil | Value::Number(_) => {}
+            Value::String(string) => self.mark(string),
+            Value::Function(function) => self.mark(function),
+            Value::Closure(closure) => self.mark(closure),
+            Value::Class(class) => self.mark(class),
+            Value::Instance(instance) => self.mark(instance),
+            Value::BoundMethod(bound_method) => self.mark(bound_method),
+            Value::NativeFn(native) => self.mark(native),
+        }
+    }
+
+    /// Frees every object that is not reachable from the roots marked since
+    /// the last collection.
+    pub(crate) fn collect(&mut self) {
+        self.trace_references();
+        self.strings.retain(|string| string.0.is_marked());
+        self.sweep();
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(ptr) = self.gray_stack.pop() {
+            // SAFETY: gray objects were marked, so they are still alive.
+            let object = unsafe { &ptr.as_ref().value };
+            object.trace(self);
+        }
+    }
+
+    fn sweep(&mut self) {
+        let mut bytes_freed = 0;
+
+        self.objects.retain(|ptr| {
+            // SAFETY: objects of the heap are alive until swept right here.
+            let header = unsafe { ptr.as_ref() };
+            if header.marked.replace(false) {
+                return true;
+            }
+
+            bytes_freed += mem::size_of_val(header) + header.value.size();
+            // SAFETY: the object is unreachable, so no handle to it is used
+            // anymore, and it was leaked from a `Box` in `Heap::alloc`.
+            unsafe { drop(Box::from_raw(ptr.as_ptr())) };
+            false
+        });
+
+        self.bytes_allocated -= bytes_freed;
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for ptr in self.objects.drain(..) {
+            // SAFETY: every object was leaked from a `Box` in `Heap::alloc` and
+            // is freed exactly once.
+            unsafe { drop(Box::from_raw(ptr.as_ptr())) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{Class, Instance};
+
+    #[test]
+    fn interning() {
+        let mut heap = Heap::new();
+
+        let a = heap.copy_string("lox");
+        let b = heap.take_string(String::from("lox"));
+        let c = heap.copy_string("rs");
+
+        assert!(Gc::ptr_eq(&a, &b));
+        assert!(!Gc::ptr_eq(&a, &c));
+    }
+
+    #[test]
+    fn collect() {
+        let mut heap = Heap::new();
+
+        let kept = heap.copy_string("kept");
+        heap.copy_string("garbage");
+        let bytes_allocated = heap.bytes_allocated();
+
+        heap.mark(kept);
+        heap.collect();
+
+        assert_eq!(heap.objects.len(), 1);
+        assert!(heap.bytes_allocated() < bytes_allocated);
+        assert!(Gc::ptr_eq(&heap.copy_string("kept"), &kept));
+        assert_eq!(heap.objects.len(), 1);
+        heap.copy_string("garbage");
+        assert_eq!(heap.objects.len(), 2);
+    }
+
+    #[test]
+    fn update() {
+        let mut heap = Heap::new();
+        let name = heap.copy_string("A");
+        let class = heap.alloc(Class::new(name));
+        let instance = heap.alloc(Instance::new(class));
+        let fields: Vec<_> = (0..100).map(|i| heap.copy_string(&i.to_string())).collect();
+        let bytes_allocated = heap.bytes_allocated();
+
+        for field in fields {
+            heap.update(instance, |instance| instance.set_field(field, Value::Nil));
+        }
+        assert!(instance.size() > 0);
+        assert_eq!(heap.bytes_allocated(), bytes_allocated + instance.size());
+
+        heap.collect();
+        assert_eq!(heap.bytes_allocated(), 0);
+    }
+}
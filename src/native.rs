@@ -0,0 +1,319 @@
+use std::{
+    fmt,
+    marker::PhantomData,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    bytecode::Value,
+    object::NativeFnPtr,
+    vm::{RuntimeError, Vm},
+};
+
+/// Name, arity and implementation of every function of the standard library.
+pub const STDLIB: &[(&str, usize, NativeFnPtr)] = &[
+    ("clock", 0, clock),
+    ("str", 1, str),
+    ("num", 1, num),
+    ("len", 1, len),
+    ("substr", 3, substr),
+    ("type", 1, type_),
+    ("floor", 1, floor),
+    ("sqrt", 1, sqrt),
+    ("abs", 1, abs),
+];
+
+type NativeResult<'a> = Result<NativeValue<'a>, RuntimeError>;
+
+/// A call of a native function, through which it can create values.
+pub struct NativeCall<'a> {
+    vm: &'a mut Vm,
+}
+
+impl<'a> NativeCall<'a> {
+    pub(crate) fn new(vm: &'a mut Vm) -> Self {
+        NativeCall { vm }
+    }
+
+    /// Returns a string value with the given contents.
+    pub fn string(&mut self, value: String) -> NativeValue<'a> {
+        NativeValue::new(Value::String(self.vm.root_string(value)))
+    }
+}
+
+/// A value passed to or returned by a native function.
+///
+/// The objects a value points to are only kept alive during the call, so
+/// values are bound to it by their lifetime.
+#[derive(Clone, Copy, Debug)]
+pub struct NativeValue<'a> {
+    value: Value,
+    call: PhantomData<&'a ()>,
+}
+
+impl<'a> NativeValue<'a> {
+    pub(crate) fn new(value: Value) -> Self {
+        NativeValue {
+            value,
+            call: PhantomData,
+        }
+    }
+
+    pub(crate) fn value(self) -> Value {
+        self.value
+    }
+
+    pub fn nil() -> Self {
+        NativeValue::new(Value::Nil)
+    }
+
+    pub fn bool(value: bool) -> Self {
+        NativeValue::new(Value::Bool(value))
+    }
+
+    pub fn number(value: f64) -> Self {
+        NativeValue::new(Value::Number(value))
+    }
+
+    pub fn is_nil(self) -> bool {
+        matches!(self.value, Value::Nil)
+    }
+
+    pub fn as_bool(self) -> Option<bool> {
+        match self.value {
+            Value::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(self) -> Option<f64> {
+        match self.value {
+            Value::Number(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> Option<&'a str> {
+        match self.value {
+            // SAFETY: the string is alive until the call returns, as the VM
+            // roots the arguments and the strings created during the call.
+            Value::String(string) => Some(unsafe { string.as_ref() }.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Only `nil` and `false` are falsey, every other value is truthy.
+    pub fn is_falsey(self) -> bool {
+        self.value.is_falsey()
+    }
+
+    pub fn type_name(self) -> &'static str {
+        self.value.type_name()
+    }
+}
+
+impl fmt::Display for NativeValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value)
+    }
+}
+
+/// Returns the number of seconds elapsed since the Unix epoch.
+fn clock<'a>(_call: &mut NativeCall<'a>, _args: &[NativeValue<'a>]) -> NativeResult<'a> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    Ok(NativeValue::number(elapsed.as_secs_f64()))
+}
+
+/// Converts any value to its printed representation.
+fn str<'a>(call: &mut NativeCall<'a>, args: &[NativeValue<'a>]) -> NativeResult<'a> {
+    match args[0].as_str() {
+        Some(_) => Ok(args[0]),
+        None => Ok(call.string(args[0].to_string())),
+    }
+}
+
+/// Parses a string into a number.
+fn num<'a>(_call: &mut NativeCall<'a>, args: &[NativeValue<'a>]) -> NativeResult<'a> {
+    let string = string_arg("num", args[0])?;
+
+    string
+        .trim()
+        .parse()
+        .map(NativeValue::number)
+        .map_err(|_| invalid_argument("num", "a numeric string"))
+}
+
+/// Returns the number of characters of a string.
+fn len<'a>(_call: &mut NativeCall<'a>, args: &[NativeValue<'a>]) -> NativeResult<'a> {
+    let string = string_arg("len", args[0])?;
+
+    Ok(NativeValue::number(string.chars().count() as f64))
+}
+
+/// Returns the `length` characters of a string starting at `start`.
+fn substr<'a>(call: &mut NativeCall<'a>, args: &[NativeValue<'a>]) -> NativeResult<'a> {
+    let string = string_arg("substr", args[0])?;
+    let string_length = string.chars().count();
+    let start = index_arg("substr", args[1], string_length)?;
+    let length = index_arg("substr", args[2], string_length)?;
+
+    if length > string_length - start {
+        return Err(invalid_argument("substr", "a range within the string"));
+    }
+
+    let substring = string.chars().skip(start).take(length).collect();
+    Ok(call.string(substring))
+}
+
+/// Returns the name of the type of a value.
+fn type_<'a>(call: &mut NativeCall<'a>, args: &[NativeValue<'a>]) -> NativeResult<'a> {
+    Ok(call.string(args[0].type_name().to_string()))
+}
+
+fn floor<'a>(_call: &mut NativeCall<'a>, args: &[NativeValue<'a>]) -> NativeResult<'a> {
+    Ok(NativeValue::number(number_arg("floor", args[0])?.floor()))
+}
+
+fn sqrt<'a>(_call: &mut NativeCall<'a>, args: &[NativeValue<'a>]) -> NativeResult<'a> {
+    Ok(NativeValue::number(number_arg("sqrt", args[0])?.sqrt()))
+}
+
+fn abs<'a>(_call: &mut NativeCall<'a>, args: &[NativeValue<'a>]) -> NativeResult<'a> {
+    Ok(NativeValue::number(number_arg("abs", args[0])?.abs()))
+}
+
+fn invalid_argument(function: &'static str, expected: &'static str) -> RuntimeError {
+    RuntimeError::InvalidArgument { function, expected }
+}
+
+fn number_arg(function: &'static str, value: NativeValue) -> Result<f64, RuntimeError> {
+    value
+        .as_number()
+        .ok_or_else(|| invalid_argument(function, "a number"))
+}
+
+fn string_arg<'a>(function: &'static str, value: NativeValue<'a>) -> Result<&'a str, RuntimeError> {
+    value
+        .as_str()
+        .ok_or_else(|| invalid_argument(function, "a string"))
+}
+
+/// Converts an index or a length into a string of `max` characters.
+fn index_arg(
+    function: &'static str,
+    value: NativeValue,
+    max: usize,
+) -> Result<usize, RuntimeError> {
+    match value.as_number() {
+        Some(value) if value.is_finite() && value >= 0.0 && value.fract() == 0.0 => {
+            if value <= max as f64 {
+                Ok(value as usize)
+            } else {
+                Err(invalid_argument(function, "a range within the string"))
+            }
+        }
+        _ => Err(invalid_argument(function, "a non-negative integer")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, memory::Heap};
+
+    fn bare_vm() -> Vm {
+        let mut heap = Heap::new();
+        let chunk = compile("", &mut heap).unwrap();
+        Vm::init_bare(chunk, heap).unwrap()
+    }
+
+    fn string(vm: &mut Vm, value: &str) -> Value {
+        Value::String(vm.root_string(value.to_string()))
+    }
+
+    fn call(vm: &mut Vm, function: NativeFnPtr, args: &[Value]) -> Result<String, RuntimeError> {
+        let args: Vec<_> = args.iter().map(|&arg| NativeValue::new(arg)).collect();
+        function(&mut NativeCall::new(vm), &args).map(|value| value.to_string())
+    }
+
+    fn invalid_argument_of(result: Result<String, RuntimeError>, function: &str) -> bool {
+        matches!(
+            result,
+            Err(RuntimeError::InvalidArgument { function: name, .. }) if name == function
+        )
+    }
+
+    #[test]
+    fn conversions() {
+        let mut vm = bare_vm();
+        let number = string(&mut vm, " 42.5");
+        let lox = string(&mut vm, "lox");
+
+        assert_eq!(call(&mut vm, str, &[Value::Number(1.5)]).unwrap(), "1.5");
+        assert_eq!(call(&mut vm, str, &[Value::Nil]).unwrap(), "nil");
+        assert_eq!(call(&mut vm, num, &[number]).unwrap(), "42.5");
+        assert!(invalid_argument_of(call(&mut vm, num, &[lox]), "num"));
+        assert!(invalid_argument_of(
+            call(&mut vm, num, &[Value::Bool(true)]),
+            "num"
+        ));
+    }
+
+    #[test]
+    fn strings() {
+        let mut vm = bare_vm();
+        let hello = string(&mut vm, "héllo");
+
+        assert_eq!(call(&mut vm, len, &[hello]).unwrap(), "5");
+        assert!(invalid_argument_of(
+            call(&mut vm, len, &[Value::Nil]),
+            "len"
+        ));
+
+        let args = [hello, Value::Number(1.0), Value::Number(3.0)];
+        assert_eq!(call(&mut vm, substr, &args).unwrap(), "éll");
+        let args = [hello, Value::Number(3.0), Value::Number(3.0)];
+        assert!(invalid_argument_of(call(&mut vm, substr, &args), "substr"));
+        let args = [hello, Value::Number(0.5), Value::Number(1.0)];
+        assert!(invalid_argument_of(call(&mut vm, substr, &args), "substr"));
+        let args = [hello, Value::Number(1.0), Value::Number(usize::MAX as f64)];
+        assert!(invalid_argument_of(call(&mut vm, substr, &args), "substr"));
+        let args = [hello, Value::Number(1e300), Value::Number(1.0)];
+        assert!(invalid_argument_of(call(&mut vm, substr, &args), "substr"));
+        let args = [hello, Value::Number(f64::INFINITY), Value::Number(1.0)];
+        assert!(invalid_argument_of(call(&mut vm, substr, &args), "substr"));
+        let args = [hello, Value::Number(5.0), Value::Number(0.0)];
+        assert_eq!(call(&mut vm, substr, &args).unwrap(), "");
+    }
+
+    #[test]
+    fn types() {
+        let mut vm = bare_vm();
+        let lox = string(&mut vm, "lox");
+
+        assert_eq!(call(&mut vm, type_, &[Value::Nil]).unwrap(), "nil");
+        assert_eq!(call(&mut vm, type_, &[Value::Bool(false)]).unwrap(), "bool");
+        assert_eq!(
+            call(&mut vm, type_, &[Value::Number(1.0)]).unwrap(),
+            "number"
+        );
+        assert_eq!(call(&mut vm, type_, &[lox]).unwrap(), "string");
+    }
+
+    #[test]
+    fn math() {
+        let mut vm = bare_vm();
+
+        assert_eq!(call(&mut vm, floor, &[Value::Number(-1.5)]).unwrap(), "-2");
+        assert_eq!(call(&mut vm, sqrt, &[Value::Number(16.0)]).unwrap(), "4");
+        assert_eq!(call(&mut vm, abs, &[Value::Number(-3.0)]).unwrap(), "3");
+        assert!(invalid_argument_of(
+            call(&mut vm, abs, &[Value::Nil]),
+            "abs"
+        ));
+        assert!(call(&mut vm, clock, &[]).unwrap().parse::<f64>().unwrap() > 0.0);
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt, mem,
+};
+
+use crate::{
+    bytecode::{Chunk, Value},
+    memory::{Gc, Heap, Trace},
+    native::{NativeCall, NativeValue},
+    vm::RuntimeError,
+};
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct LoxString {
+    value: String,
+}
+
+impl LoxString {
+    pub fn new(value: String) -> Self {
+        LoxString { value }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+}
+
+impl Trace for LoxString {
+    fn trace(&self, _heap: &mut Heap) {}
+
+    fn size(&self) -> usize {
+        self.value.capacity()
+    }
+}
+
+impl fmt::Display for LoxString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value)
+    }
+}
+
+/// Where a closure finds one of its captured variables when it is created.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct UpvalueDescriptor {
+    /// Whether the variable is a local of the enclosing function, rather than
+    /// one of its own upvalues.
+    pub is_local: bool,
+    /// Stack slot of the local, or index of the upvalue, in the enclosing function.
+    pub index: u8,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub arity: usize,
+    pub chunk: Chunk,
+    pub name: Option<Gc<LoxString>>,
+    pub upvalues: Vec<UpvalueDescriptor>,
+}
+
+impl Function {
+    pub fn new(name: Option<Gc<LoxString>>) -> Self {
+        Function {
+            arity: 0,
+            chunk: Chunk::new(),
+            name,
+            upvalues: Vec::new(),
+        }
+    }
+}
+
+impl Trace for Function {
+    fn trace(&self, heap: &mut Heap) {
+        if let Some(name) = self.name {
+            heap.mark(name);
+        }
+        for &constant in &self.chunk.constants {
+            heap.mark_value(constant);
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.chunk.size() + self.upvalues.capacity() * mem::size_of::<UpvalueDescriptor>()
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum UpvalueState {
+    /// The variable still lives on the VM stack, at the given index.
+    Open(usize),
+    /// The variable was moved off the stack when its scope ended.
+    Closed(Value),
+}
+
+/// A variable captured by a closure.
+///
+/// Upvalues are shared by every closure capturing the same variable, so that
+/// they all observe each other's assignments.
+pub struct Upvalue {
+    state: Cell<UpvalueState>,
+}
+
+impl Upvalue {
+    pub fn new(slot: usize) -> Self {
+        Upvalue {
+            state: Cell::new(UpvalueState::Open(slot)),
+        }
+    }
+
+    pub fn state(&self) -> UpvalueState {
+        self.state.get()
+    }
+
+    pub fn set_state(&self, state: UpvalueState) {
+        self.state.set(state);
+    }
+}
+
+impl Trace for Upvalue {
+    fn trace(&self, heap: &mut Heap) {
+        if let UpvalueState::Closed(value) = self.state() {
+            heap.mark_value(value);
+        }
+    }
+}
+
+impl fmt::Debug for Upvalue {
+    // Closed upvalues can hold the closure capturing them, so their value is
+    // left out to avoid infinite recursion.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.state() {
+            UpvalueState::Open(slot) => write!(f, "Upvalue(open {})", slot),
+            UpvalueState::Closed(_) => write!(f, "Upvalue(closed)"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Gc<Function>,
+    pub upvalues: Vec<Gc<Upvalue>>,
+}
+
+impl Closure {
+    pub fn new(function: Gc<Function>, upvalues: Vec<Gc<Upvalue>>) -> Self {
+        Closure { function, upvalues }
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark(self.function);
+        for &upvalue in &self.upvalues {
+            heap.mark(upvalue);
+        }
+    }
+}
+
+impl fmt::Display for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.function)
+    }
+}
+
+/// Size of an entry of a table of fields or methods, to account for the
+/// memory they hold.
+const TABLE_ENTRY_SIZE: usize = mem::size_of::<(Gc<LoxString>, Value)>();
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: Gc<LoxString>,
+    pub(crate) methods: RefCell<HashMap<Gc<LoxString>, Gc<Closure>>>,
+}
+
+impl Class {
+    pub fn new(name: Gc<LoxString>) -> Self {
+        Class {
+            name,
+            methods: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn method(&self, name: Gc<LoxString>) -> Option<Gc<Closure>> {
+        self.methods.borrow().get(&name).cloned()
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark(self.name);
+        for (&name, &method) in self.methods.borrow().iter() {
+            heap.mark(name);
+            heap.mark(method);
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.methods.borrow().capacity() * TABLE_ENTRY_SIZE
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+pub struct Instance {
+    pub class: Gc<Class>,
+    pub(crate) fields: RefCell<HashMap<Gc<LoxString>, Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Gc<Class>) -> Self {
+        Instance {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn field(&self, name: Gc<LoxString>) -> Option<Value> {
+        self.fields.borrow().get(&name).cloned()
+    }
+
+    pub(crate) fn set_field(&self, name: Gc<LoxString>, value: Value) {
+        self.fields.borrow_mut().insert(name, value);
+    }
+}
+
+impl Trace for Instance {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark(self.class);
+        for (&name, &value) in self.fields.borrow().iter() {
+            heap.mark(name);
+            heap.mark_value(value);
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.fields.borrow().capacity() * TABLE_ENTRY_SIZE
+    }
+}
+
+impl fmt::Debug for Instance {
+    // Fields can hold the instance itself, so they are left out to avoid
+    // infinite recursion.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instance({})", self.class.name)
+    }
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} instance", self.class.name)
+    }
+}
+
+/// A method bound to the instance it was accessed on, which becomes `this`
+/// when it is called.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Gc<Closure>,
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: Gc<Closure>) -> Self {
+        BoundMethod { receiver, method }
+    }
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_value(self.receiver);
+        heap.mark(self.method);
+    }
+}
+
+impl fmt::Display for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.method)
+    }
+}
+
+/// Signature of Rust functions callable from Lox.
+///
+/// Natives only see the values of a call as `NativeValue`s, which can't
+/// outlive it: nothing keeps the objects they point to alive afterwards.
+pub type NativeFnPtr =
+    for<'a> fn(&mut NativeCall<'a>, &[NativeValue<'a>]) -> Result<NativeValue<'a>, RuntimeError>;
+
+/// A Rust function exposed to Lox programs.
+pub struct NativeFn {
+    pub name: Gc<LoxString>,
+    pub arity: usize,
+    pub function: NativeFnPtr,
+}
+
+impl NativeFn {
+    pub fn new(name: Gc<LoxString>, arity: usize, function: NativeFnPtr) -> Self {
+        NativeFn {
+            name,
+            arity,
+            function,
+        }
+    }
+}
+
+impl Trace for NativeFn {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark(self.name);
+    }
+}
+
+impl fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NativeFn({}/{})", self.name, self.arity)
+    }
+}
+
+impl fmt::Display for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
@@ -0,0 +1,528 @@
+use std::{error, fmt};
+
+use crate::{
+    bytecode::{Chunk, OpCode, Value},
+    object::Function,
+};
+
+/// A reason for a chunk to be rejected by the verifier.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum VerifyErrorKind {
+    UnknownOpcode(u8),
+    /// The operands of the last instruction run past the end of the code.
+    TruncatedInstruction,
+    ConstantOutOfRange(usize),
+    /// A constant operand refers to a value of the wrong type.
+    ConstantType(&'static str),
+    LocalOutOfRange(usize),
+    UpvalueOutOfRange(usize),
+    /// A jump leads outside of the code, or in the middle of an instruction.
+    InvalidJump(usize),
+    StackUnderflow,
+    /// Two paths reach the same instruction with different stack depths.
+    InconsistentStack {
+        expected: usize,
+        got: usize,
+    },
+    /// Execution can run past the end of the code without returning.
+    MissingReturn,
+}
+
+impl fmt::Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyErrorKind::UnknownOpcode(byte) => write!(f, "Unknown opcode {}.", byte),
+            VerifyErrorKind::TruncatedInstruction => write!(f, "Truncated instruction."),
+            VerifyErrorKind::ConstantOutOfRange(index) => {
+                write!(f, "Constant {} is out of range.", index)
+            }
+            VerifyErrorKind::ConstantType(expected) => write!(f, "Constant must be {}.", expected),
+            VerifyErrorKind::LocalOutOfRange(slot) => {
+                write!(f, "Local slot {} is out of range.", slot)
+            }
+            VerifyErrorKind::UpvalueOutOfRange(index) => {
+                write!(f, "Upvalue {} is out of range.", index)
+            }
+            VerifyErrorKind::InvalidJump(target) => {
+                write!(f, "Jump target {} is not an instruction.", target)
+            }
+            VerifyErrorKind::StackUnderflow => write!(f, "Stack underflow."),
+            VerifyErrorKind::InconsistentStack { expected, got } => write!(
+                f,
+                "Stack depth is {} on one path and {} on another.",
+                expected, got
+            ),
+            VerifyErrorKind::MissingReturn => write!(f, "Missing return at end of chunk."),
+        }
+    }
+}
+
+/// A malformed instruction found by `verify`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VerifyError {
+    /// Name of the function holding the chunk, `None` for the top-level script.
+    pub function: Option<String>,
+    /// Offset of the malformed instruction in the chunk.
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid bytecode at offset {} in ", self.offset)?;
+        match &self.function {
+            Some(name) => write!(f, "{}(): {}", name, self.kind),
+            None => write!(f, "script: {}", self.kind),
+        }
+    }
+}
+
+impl error::Error for VerifyError {}
+
+/// Checks that running `function` can't read past its code, its constants,
+/// its locals or its upvalues, nor pop an empty stack, and that it always
+/// ends with a return. The functions it declares are checked as well.
+///
+/// Returns the maximum depth the stack of a call to `function` can reach,
+/// counting the callee and its arguments.
+pub fn verify(function: &Function) -> Result<usize, VerifyError> {
+    let verifier = Verifier {
+        function,
+        chunk: &function.chunk,
+    };
+
+    let boundaries = verifier.check_instructions()?;
+    verifier.check_stack(&boundaries)
+}
+
+struct Verifier<'a> {
+    function: &'a Function,
+    chunk: &'a Chunk,
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&self, offset: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError {
+            function: self.function.name.map(|name| name.to_string()),
+            offset,
+            kind,
+        }
+    }
+
+    /// Decodes the code from start to end, checking the operands that don't
+    /// depend on the state of the stack, and returns whether each offset
+    /// starts an instruction.
+    fn check_instructions(&self) -> Result<Vec<bool>, VerifyError> {
+        let code = &self.chunk.code;
+        let mut boundaries = vec![false; code.len()];
+        let mut jumps = Vec::new();
+
+        let mut offset = 0;
+        while offset < code.len() {
+            let op_code = OpCode::from_byte(code[offset])
+                .ok_or_else(|| self.error(offset, VerifyErrorKind::UnknownOpcode(code[offset])))?;
+            if offset + op_code.width() > code.len() {
+                return Err(self.error(offset, VerifyErrorKind::TruncatedInstruction));
+            }
+            boundaries[offset] = true;
+
+            match op_code {
+                OpCode::Constant | OpCode::ConstantLong => {
+                    self.constant(offset, self.index(op_code, offset))?;
+                }
+                OpCode::DefineGlobal
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobal
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobal
+                | OpCode::SetGlobalLong
+                | OpCode::GetProperty
+                | OpCode::GetPropertyLong
+                | OpCode::SetProperty
+                | OpCode::SetPropertyLong
+                | OpCode::GetSuper
+                | OpCode::GetSuperLong
+                | OpCode::Invoke
+                | OpCode::InvokeLong
+                | OpCode::SuperInvoke
+                | OpCode::SuperInvokeLong
+                | OpCode::Class
+                | OpCode::ClassLong
+                | OpCode::Method
+                | OpCode::MethodLong => {
+                    let index = self.index(op_code, offset);
+                    if !matches!(self.constant(offset, index)?, Value::String(_)) {
+                        return Err(self.error(offset, VerifyErrorKind::ConstantType("a string")));
+                    }
+                }
+                OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                    self.upvalue(offset, self.chunk.read_byte(offset + 1) as usize)?;
+                }
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                    jumps.push((offset, self.jump_target(op_code, offset)));
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let index = self.index(op_code, offset);
+                    let function = match self.constant(offset, index)? {
+                        Value::Function(function) => function,
+                        _ => {
+                            return Err(
+                                self.error(offset, VerifyErrorKind::ConstantType("a function"))
+                            )
+                        }
+                    };
+
+                    for upvalue in function.upvalues.iter().filter(|upvalue| !upvalue.is_local) {
+                        self.upvalue(offset, upvalue.index as usize)?;
+                    }
+                    verify(&function)?;
+                }
+                _ => {}
+            }
+
+            offset += op_code.width();
+        }
+
+        for (offset, target) in jumps {
+            match target {
+                Some(target) if boundaries.get(target) == Some(&true) => {}
+                _ => {
+                    let target = target.unwrap_or_default();
+                    return Err(self.error(offset, VerifyErrorKind::InvalidJump(target)));
+                }
+            }
+        }
+
+        Ok(boundaries)
+    }
+
+    /// Follows every path through the code, tracking the depth of the stack
+    /// before each instruction, and returns the maximum depth reached.
+    fn check_stack(&self, boundaries: &[bool]) -> Result<usize, VerifyError> {
+        let code_len = self.chunk.code.len();
+        // The callee and its arguments are on the stack when the call starts.
+        let initial_depth = self.function.arity + 1;
+        let mut depths: Vec<Option<usize>> = vec![None; code_len];
+        let mut max_depth = initial_depth;
+        let mut pending = vec![(0, initial_depth)];
+
+        while let Some((offset, depth)) = pending.pop() {
+            if offset >= code_len {
+                // Only falling through the last instruction can get here, as
+                // jump targets were checked.
+                let last = boundaries.iter().rposition(|&start| start);
+                return Err(self.error(last.unwrap_or_default(), VerifyErrorKind::MissingReturn));
+            }
+
+            match depths[offset] {
+                Some(expected) if expected == depth => continue,
+                Some(expected) => {
+                    return Err(self.error(
+                        offset,
+                        VerifyErrorKind::InconsistentStack {
+                            expected,
+                            got: depth,
+                        },
+                    ))
+                }
+                None => depths[offset] = Some(depth),
+            }
+
+            // Instructions were all decoded by `check_instructions`.
+            let op_code = self.chunk.code_at(offset).unwrap();
+            let (pops, pushes) = self.stack_effect(op_code, offset, depth)?;
+            if pops > depth {
+                return Err(self.error(offset, VerifyErrorKind::StackUnderflow));
+            }
+            let depth = depth - pops + pushes;
+            max_depth = max_depth.max(depth);
+
+            let next = offset + op_code.width();
+            match op_code {
+                OpCode::Return => {}
+                OpCode::Jump | OpCode::Loop => {
+                    pending.push((self.jump_target(op_code, offset).unwrap(), depth));
+                }
+                OpCode::JumpIfFalse => {
+                    pending.push((self.jump_target(op_code, offset).unwrap(), depth));
+                    pending.push((next, depth));
+                }
+                _ => pending.push((next, depth)),
+            }
+        }
+
+        Ok(max_depth)
+    }
+
+    /// Returns how many values the instruction at `offset` pops, then pushes,
+    /// checking the locals it refers to against the stack `depth`.
+    fn stack_effect(
+        &self,
+        op_code: OpCode,
+        offset: usize,
+        depth: usize,
+    ) -> Result<(usize, usize), VerifyError> {
+        let operand = |index: usize| self.chunk.read_byte(offset + index) as usize;
+        let local = |slot: usize| {
+            if slot < depth {
+                Ok(())
+            } else {
+                Err(self.error(offset, VerifyErrorKind::LocalOutOfRange(slot)))
+            }
+        };
+
+        let effect = match op_code {
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobal
+            | OpCode::GetGlobalLong
+            | OpCode::GetUpvalue
+            | OpCode::Class
+            | OpCode::ClassLong => (0, 1),
+            OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::DefineGlobalLong
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return => (1, 0),
+            OpCode::GetLocal => {
+                local(operand(1))?;
+                (0, 1)
+            }
+            OpCode::SetLocal => {
+                local(operand(1))?;
+                (1, 1)
+            }
+            OpCode::SetGlobal
+            | OpCode::SetGlobalLong
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::GetPropertyLong
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::JumpIfFalse => (1, 1),
+            OpCode::SetProperty
+            | OpCode::SetPropertyLong
+            | OpCode::GetSuper
+            | OpCode::GetSuperLong
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Substract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Inherit
+            | OpCode::Method
+            | OpCode::MethodLong => (2, 1),
+            OpCode::Jump | OpCode::Loop => (0, 0),
+            // The callee, or the receiver, and the arguments are replaced by
+            // the result of the call.
+            OpCode::Call => (operand(1) + 1, 1),
+            OpCode::Invoke => (operand(2) + 1, 1),
+            OpCode::InvokeLong => (operand(4) + 1, 1),
+            // The superclass is popped too.
+            OpCode::SuperInvoke => (operand(2) + 2, 1),
+            OpCode::SuperInvokeLong => (operand(4) + 2, 1),
+            OpCode::Closure | OpCode::ClosureLong => {
+                let index = self.index(op_code, offset);
+                if let Value::Function(function) = self.chunk.constants[index] {
+                    for upvalue in function.upvalues.iter().filter(|upvalue| upvalue.is_local) {
+                        local(upvalue.index as usize)?;
+                    }
+                }
+                (0, 1)
+            }
+        };
+
+        Ok(effect)
+    }
+
+    /// Decodes the constant index operand of the instruction at `offset`.
+    fn index(&self, op_code: OpCode, offset: usize) -> usize {
+        if op_code.is_long() {
+            self.chunk.read_long(offset + 1)
+        } else {
+            self.chunk.read_byte(offset + 1) as usize
+        }
+    }
+
+    fn constant(&self, offset: usize, index: usize) -> Result<Value, VerifyError> {
+        self.chunk
+            .constant_at(index)
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::ConstantOutOfRange(index)))
+    }
+
+    fn upvalue(&self, offset: usize, index: usize) -> Result<(), VerifyError> {
+        if index < self.function.upvalues.len() {
+            Ok(())
+        } else {
+            Err(self.error(offset, VerifyErrorKind::UpvalueOutOfRange(index)))
+        }
+    }
+
+    /// Offset the jump at `offset` leads to, `None` if it is before the code.
+    fn jump_target(&self, op_code: OpCode, offset: usize) -> Option<usize> {
+        let jump = self.chunk.read_short(offset + 1) as usize;
+        let next = offset + op_code.width();
+        if op_code == OpCode::Loop {
+            next.checked_sub(jump)
+        } else {
+            Some(next + jump)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, lexer::Position, memory::Heap};
+
+    fn chunk(code: &[u8], constants: &[Value]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for &byte in code {
+            chunk.write(byte, Position::init());
+        }
+        chunk.constants.extend_from_slice(constants);
+
+        chunk
+    }
+
+    fn verify_script(chunk: Chunk) -> Result<usize, VerifyError> {
+        let mut script = Function::new(None);
+        script.chunk = chunk;
+
+        verify(&script)
+    }
+
+    fn error_kind(code: &[u8], constants: &[Value]) -> VerifyErrorKind {
+        verify_script(chunk(code, constants)).unwrap_err().kind
+    }
+
+    #[test]
+    fn compiled_code() {
+        let mut heap = Heap::new();
+        let source = "
+            fun f(a, b) { var c = a + b; return c; }
+            class A < B { g() { return super.g(f(1, 2)); } }
+            for (var i = 0; i < 3 and i > -1; i = i + 1) print f(i, 1) or nil;
+        ";
+        let chunk = compile(source, &mut heap).unwrap();
+        assert_eq!(verify_script(chunk), Ok(5));
+
+        let chunk = compile("print 1 + 2;", &mut heap).unwrap();
+        assert_eq!(verify_script(chunk), Ok(3));
+    }
+
+    #[test]
+    fn instructions() {
+        assert_eq!(error_kind(&[255], &[]), VerifyErrorKind::UnknownOpcode(255));
+        assert_eq!(
+            error_kind(&[OpCode::Constant as u8], &[]),
+            VerifyErrorKind::TruncatedInstruction
+        );
+        assert_eq!(
+            error_kind(
+                &[OpCode::Constant as u8, 1, OpCode::Return as u8],
+                &[Value::Nil]
+            ),
+            VerifyErrorKind::ConstantOutOfRange(1)
+        );
+        assert_eq!(
+            error_kind(
+                &[OpCode::GetGlobal as u8, 0, OpCode::Return as u8],
+                &[Value::Nil]
+            ),
+            VerifyErrorKind::ConstantType("a string")
+        );
+        assert_eq!(
+            error_kind(&[OpCode::GetUpvalue as u8, 0, OpCode::Return as u8], &[]),
+            VerifyErrorKind::UpvalueOutOfRange(0)
+        );
+    }
+
+    #[test]
+    fn jumps() {
+        let into_operand = [
+            OpCode::Jump as u8,
+            0,
+            1,
+            OpCode::Constant as u8,
+            0,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(
+            error_kind(&into_operand, &[Value::Nil]),
+            VerifyErrorKind::InvalidJump(4)
+        );
+        assert_eq!(
+            error_kind(&[OpCode::Loop as u8, 0, 4, OpCode::Return as u8], &[]),
+            VerifyErrorKind::InvalidJump(0)
+        );
+        assert_eq!(
+            error_kind(&[OpCode::Jump as u8, 0, 0], &[]),
+            VerifyErrorKind::InvalidJump(3)
+        );
+    }
+
+    #[test]
+    fn stack() {
+        assert_eq!(
+            error_kind(&[OpCode::Add as u8, OpCode::Return as u8], &[]),
+            VerifyErrorKind::StackUnderflow
+        );
+        assert_eq!(
+            error_kind(&[OpCode::GetLocal as u8, 1, OpCode::Return as u8], &[]),
+            VerifyErrorKind::LocalOutOfRange(1)
+        );
+
+        let unbalanced = [
+            OpCode::True as u8,
+            OpCode::JumpIfFalse as u8,
+            0,
+            1,
+            OpCode::Nil as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        let error = verify_script(chunk(&unbalanced, &[])).unwrap_err();
+        assert_eq!(error.offset, 5);
+        assert_eq!(
+            error.kind,
+            VerifyErrorKind::InconsistentStack {
+                expected: 3,
+                got: 2
+            }
+        );
+    }
+
+    #[test]
+    fn missing_return() {
+        assert_eq!(error_kind(&[], &[]), VerifyErrorKind::MissingReturn);
+        assert_eq!(
+            error_kind(&[OpCode::Nil as u8, OpCode::Pop as u8], &[]),
+            VerifyErrorKind::MissingReturn
+        );
+    }
+
+    #[test]
+    fn nested_function() {
+        let mut heap = Heap::new();
+        let mut function = Function::new(Some(heap.copy_string("f")));
+        function.chunk = chunk(&[OpCode::Nil as u8], &[]);
+        let function = Value::Function(heap.alloc(function));
+
+        let script = chunk(
+            &[OpCode::Closure as u8, 0, OpCode::Return as u8],
+            &[function],
+        );
+        let error = verify_script(script).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid bytecode at offset 0 in f(): Missing return at end of chunk."
+        );
+    }
+}
@@ -1,113 +1,920 @@
-use std::{convert::From, error, fmt};
+use std::{
+    collections::HashMap,
+    error, fmt,
+    io::{self, Write},
+};
 
-use crate::bytecode::{disassemble_instruction, Chunk, OpCode, Value};
+use crate::{
+    bytecode::{disassemble_instruction, Chunk, OpCode, Value},
+    compiler::compile,
+    diagnostic::Diagnostic,
+    memory::{Gc, Heap, Trace},
+    native::{NativeCall, NativeValue, STDLIB},
+    object::{
+        BoundMethod, Class, Closure, Function, Instance, LoxString, NativeFn, NativeFnPtr, Upvalue,
+        UpvalueState,
+    },
+    verifier::{verify, VerifyError},
+};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum InterpretError {
-    CompileError,
-    RuntimeError(RuntimeError),
+    CompileError(Vec<Diagnostic>),
+    /// The compiled chunk was rejected by the verifier.
+    InvalidChunk(VerifyError),
+    RuntimeError {
+        error: RuntimeError,
+        /// Line of the instruction that failed.
+        line: usize,
+        /// Column of the token the failed instruction was compiled from.
+        column: usize,
+        /// Call stack at the time of the error, innermost call first.
+        trace: Vec<TraceFrame>,
+    },
 }
 
+/// Rendered the way clox reports errors: the message, then one line per frame
+/// of the call stack.
 impl fmt::Display for InterpretError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match *self {
-            InterpretError::CompileError => write!(f, "compile error"),
-            InterpretError::RuntimeError(err) => write!(f, "runtime error: {}", err),
+        match self {
+            InterpretError::CompileError(diagnostics) => {
+                for (idx, diagnostic) in diagnostics.iter().enumerate() {
+                    if idx > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            InterpretError::InvalidChunk(error) => write!(f, "{}", error),
+            InterpretError::RuntimeError { error, trace, .. } => {
+                write!(f, "{}", error)?;
+                for frame in trace {
+                    write!(f, "\n{}", frame)?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+impl error::Error for InterpretError {}
+
+impl From<VerifyError> for InterpretError {
+    fn from(error: VerifyError) -> InterpretError {
+        InterpretError::InvalidChunk(error)
+    }
+}
+
+/// A call that was running when a runtime error occurred.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TraceFrame {
+    /// Name of the called function, `None` for the top-level script.
+    pub function: Option<String>,
+    /// Line being executed by the call.
+    pub line: usize,
+    /// Column of the token being executed by the call.
+    pub column: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum RuntimeError {
     InvalidChunkError,
     StackUnderflow,
-    TypeError,
+    /// An operator was applied to operands of the wrong type.
+    TypeError(&'static str),
+    UndefinedVariable(String),
+    NotCallable,
+    NotAnInstance,
+    UndefinedProperty(String),
+    SuperclassNotClass,
+    InvalidArgument {
+        function: &'static str,
+        expected: &'static str,
+    },
+    ArityMismatch {
+        expected: usize,
+        got: usize,
+    },
+    StackOverflow,
+    OutputError(io::ErrorKind),
+    /// The VM was asked to run again after its program returned or failed.
+    Finished,
 }
 
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match *self {
-            RuntimeError::InvalidChunkError => write!(f, "malformed chunk"),
-            RuntimeError::StackUnderflow => write!(f, "stack underflow"),
-            RuntimeError::TypeError => write!(f, "type error"),
+        match self {
+            RuntimeError::InvalidChunkError => write!(f, "Malformed chunk."),
+            RuntimeError::StackUnderflow => write!(f, "Stack underflow."),
+            RuntimeError::TypeError(message) => write!(f, "{}", message),
+            RuntimeError::UndefinedVariable(name) => write!(f, "Undefined variable '{}'.", name),
+            RuntimeError::NotCallable => write!(f, "Can only call functions and classes."),
+            RuntimeError::NotAnInstance => write!(f, "Only instances have properties."),
+            RuntimeError::UndefinedProperty(name) => write!(f, "Undefined property '{}'.", name),
+            RuntimeError::SuperclassNotClass => write!(f, "Superclass must be a class."),
+            RuntimeError::InvalidArgument { function, expected } => {
+                write!(f, "{}() expects {}.", function, expected)
+            }
+            RuntimeError::ArityMismatch { expected, got } => {
+                write!(f, "Expected {} arguments but got {}.", expected, got)
+            }
+            RuntimeError::StackOverflow => write!(f, "Stack overflow."),
+            RuntimeError::OutputError(kind) => write!(f, "Failed to write output: {:?}.", kind),
+            RuntimeError::Finished => write!(f, "The program has already run."),
         }
     }
 }
 
-impl From<RuntimeError> for InterpretError {
-    fn from(error: RuntimeError) -> InterpretError {
-        InterpretError::RuntimeError(error)
-    }
-}
+pub type InterpretResult = Result<(), InterpretError>;
 
-impl error::Error for InterpretError {}
+/// Default limit on the number of nested calls.
+pub const DEFAULT_MAX_FRAMES: usize = 256;
 
-pub type InterpretResult = Result<(), InterpretError>;
+#[derive(Clone, Copy, Debug)]
+struct CallFrame {
+    closure: Gc<Closure>,
+    ip: usize,
+    /// Index of the first stack slot of the frame, holding the called function.
+    slot: usize,
+}
 
-#[derive(Clone, Debug)]
 pub struct Vm {
-    chunk: Chunk,
-    program_counter: usize,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
+    globals: HashMap<Gc<LoxString>, Value>,
+    /// Upvalues still pointing into the stack, sorted by stack slot.
+    open_upvalues: Vec<Gc<Upvalue>>,
+    heap: Heap,
+    /// Name of class initializers, interned once to look them up quickly.
+    init_string: Gc<LoxString>,
+    output: Box<dyn Write>,
+    /// Destination of execution traces, `None` when tracing is off.
+    trace: Option<Box<dyn Write>>,
+    /// Name of the only function to trace, when set.
+    trace_function: Option<String>,
+    max_frames: usize,
 }
 
 impl Vm {
-    pub fn init(chunk: Chunk) -> Self {
-        Vm {
-            chunk,
-            program_counter: 0,
-            stack: Vec::new(),
+    /// Compiles `source` into a VM running it, with the standard library
+    /// defined.
+    pub fn new(source: &str) -> Result<Self, InterpretError> {
+        let mut heap = Heap::new();
+        let chunk = compile(source, &mut heap)?;
+
+        Ok(Vm::init(chunk, heap)?)
+    }
+
+    /// Like `new`, but without any native function, see `init_bare`.
+    pub fn new_bare(source: &str) -> Result<Self, InterpretError> {
+        let mut heap = Heap::new();
+        let chunk = compile(source, &mut heap)?;
+
+        Ok(Vm::init_bare(chunk, heap)?)
+    }
+
+    /// Creates a VM running `chunk`, with the standard library defined.
+    ///
+    /// The chunk, and the functions it declares, must pass `verify`.
+    pub fn init(chunk: Chunk, heap: Heap) -> Result<Self, VerifyError> {
+        let mut vm = Vm::init_bare(chunk, heap)?;
+        for &(name, arity, function) in STDLIB {
+            vm.define_native(name, arity, function);
         }
+
+        Ok(vm)
     }
 
+    /// Creates a VM running `chunk` without any native function, for
+    /// sandboxing programs.
+    pub fn init_bare(chunk: Chunk, mut heap: Heap) -> Result<Self, VerifyError> {
+        let mut script = Function::new(None);
+        script.chunk = chunk;
+        let max_depth = verify(&script)?;
+        let script = heap.alloc(script);
+        let script = heap.alloc(Closure::new(script, Vec::new()));
+        let init_string = heap.copy_string("init");
+
+        let mut stack = Vec::with_capacity(max_depth);
+        stack.push(Value::Closure(script));
+
+        Ok(Vm {
+            frames: vec![CallFrame {
+                closure: script,
+                ip: 0,
+                slot: 0,
+            }],
+            stack,
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            heap,
+            init_string,
+            output: Box::new(io::stdout()),
+            trace: None,
+            trace_function: None,
+            max_frames: DEFAULT_MAX_FRAMES,
+        })
+    }
+
+    /// Limits how deeply calls can nest before raising a stack overflow.
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
+    }
+
+    /// Redirects everything the program prints, which goes to stdout by default.
+    pub fn set_output<W: Write + 'static>(&mut self, output: W) {
+        self.output = Box::new(output);
+    }
+
+    /// Traces execution to `trace`: before each instruction, the contents of
+    /// the stack and the disassembled instruction are written to it.
+    pub fn set_trace<W: Write + 'static>(&mut self, trace: W) {
+        self.trace = Some(Box::new(trace));
+    }
+
+    /// Limits traces to the instructions of the functions named `function`,
+    /// or to the top-level code when it is `<script>`.
+    pub fn set_trace_function(&mut self, function: &str) {
+        self.trace_function = Some(function.to_string());
+    }
+
+    /// Exposes a Rust function to Lox programs as the global `name`.
+    ///
+    /// Natives are called like any other function, with arity checks, and the
+    /// errors they return abort the program.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFnPtr) {
+        let name = self.intern_string(name.to_string());
+        // The name is kept on the stack, so that it survives allocating the
+        // native.
+        self.push_stack(Value::String(name));
+        let native = self.alloc(NativeFn::new(name, arity, function));
+        self.stack.pop();
+
+        self.globals.insert(name, Value::NativeFn(native));
+    }
+
+    /// Runs the program to completion. A VM runs its program only once:
+    /// afterwards, `interpret` fails with `RuntimeError::Finished`.
     pub fn interpret(&mut self) -> InterpretResult {
-        loop {
-            let instruction = self.chunk.code[self.program_counter];
+        if self.frames.is_empty() {
+            return Err(self.runtime_error(RuntimeError::Finished));
+        }
+
+        self.run().map_err(|error| self.runtime_error(error))
+    }
 
-            if cfg!(debug_assertions) {
-                print!("          ");
-                for slot in &self.stack {
-                    print!("[ {} ]", slot);
+    /// Locates `error` in the program, then resets the VM.
+    fn runtime_error(&mut self, error: RuntimeError) -> InterpretError {
+        let trace: Vec<TraceFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = frame.closure.function;
+                // The instruction pointer is already past the failed
+                // instruction, or the call of the inner frame.
+                let offset = frame.ip.saturating_sub(1);
+                let position = function.chunk.position_at(offset).unwrap_or_default();
+                TraceFrame {
+                    function: function.name.map(|name| name.to_string()),
+                    line: position.line,
+                    column: position.column,
                 }
-                println!();
-                disassemble_instruction(&self.chunk, self.program_counter)
-            }
+            })
+            .collect();
+
+        self.frames.clear();
+        self.stack.clear();
+        self.open_upvalues.clear();
 
-            self.program_counter += 1;
+        InterpretError::RuntimeError {
+            error,
+            line: trace.first().map_or(0, |frame| frame.line),
+            column: trace.first().map_or(0, |frame| frame.column),
+            trace,
+        }
+    }
+
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            let frame = self.frame();
+            let function = frame.closure.function;
+            let offset = frame.ip;
+            let instruction =
+                OpCode::from_byte(self.read_byte()).ok_or(RuntimeError::InvalidChunkError)?;
+
+            if self.trace.is_some() {
+                self.trace_instruction(function, offset)
+                    .map_err(|err| RuntimeError::OutputError(err.kind()))?;
+            }
 
             macro_rules! binary_op {
-                ($op:tt) => {{
+                ($value_type:path, $op:tt) => {{
                     match (self.pop_stack()?, self.pop_stack()?) {
-                        (Value::Number(b), Value::Number(a)) => self.push_stack(Value::Number(a $op b)),
+                        (Value::Number(b), Value::Number(a)) => {
+                            self.push_stack($value_type(a $op b))
+                        }
+                        _ => return Err(RuntimeError::TypeError("Operands must be numbers.")),
                     }
                 }};
             }
 
             match instruction {
-                OpCode::Constant(idx) => {
-                    let constant = self
-                        .chunk
-                        .constant_at(idx)
-                        .ok_or(RuntimeError::InvalidChunkError)?;
+                OpCode::Constant => {
+                    let idx = self.read_byte() as usize;
+                    let constant = self.read_constant(idx)?;
                     self.push_stack(constant);
                 }
-                OpCode::Add => binary_op!(+),
-                OpCode::Substract => binary_op!(-),
-                OpCode::Multiply => binary_op!(*),
-                OpCode::Divide => binary_op!(/),
+                OpCode::Nil => self.push_stack(Value::Nil),
+                OpCode::True => self.push_stack(Value::Bool(true)),
+                OpCode::False => self.push_stack(Value::Bool(false)),
+                OpCode::Pop => {
+                    self.pop_stack()?;
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_byte();
+                    let value = self.stack_slot(slot)?;
+                    self.push_stack(value);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte();
+                    let value = self.peek_stack(0)?;
+                    let slot = self.frame().slot + slot as usize;
+                    *self
+                        .stack
+                        .get_mut(slot)
+                        .ok_or(RuntimeError::InvalidChunkError)? = value;
+                }
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let idx = self.read_index(instruction);
+                    let name = self.read_string(idx)?;
+                    let value = self.pop_stack()?;
+                    self.globals.insert(name, value);
+                }
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let idx = self.read_index(instruction);
+                    let name = self.read_string(idx)?;
+                    match self.globals.get(&name) {
+                        Some(&value) => self.push_stack(value),
+                        None => return Err(RuntimeError::UndefinedVariable(name.to_string())),
+                    }
+                }
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let idx = self.read_index(instruction);
+                    let name = self.read_string(idx)?;
+                    let value = self.peek_stack(0)?;
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => return Err(RuntimeError::UndefinedVariable(name.to_string())),
+                    }
+                }
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    let idx = self.read_index(instruction);
+                    let name = self.read_string(idx)?;
+                    let instance = match self.peek_stack(0)? {
+                        Value::Instance(instance) => instance,
+                        _ => return Err(RuntimeError::NotAnInstance),
+                    };
+
+                    match instance.field(name) {
+                        Some(value) => {
+                            self.pop_stack()?;
+                            self.push_stack(value);
+                        }
+                        None => self.bind_method(instance.class, name)?,
+                    }
+                }
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    let idx = self.read_index(instruction);
+                    let name = self.read_string(idx)?;
+                    let instance = match self.peek_stack(1)? {
+                        Value::Instance(instance) => instance,
+                        _ => return Err(RuntimeError::NotAnInstance),
+                    };
+
+                    let value = self.pop_stack()?;
+                    self.heap
+                        .update(instance, |instance| instance.set_field(name, value));
+                    self.pop_stack()?;
+                    self.push_stack(value);
+                }
+                OpCode::GetSuper | OpCode::GetSuperLong => {
+                    let idx = self.read_index(instruction);
+                    let name = self.read_string(idx)?;
+                    let superclass = match self.pop_stack()? {
+                        Value::Class(class) => class,
+                        _ => return Err(RuntimeError::InvalidChunkError),
+                    };
+
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::Equal => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+                    self.push_stack(Value::Bool(a == b));
+                }
+                OpCode::Greater => binary_op!(Value::Bool, >),
+                OpCode::Less => binary_op!(Value::Bool, <),
+                OpCode::Add => match (self.peek_stack(0)?, self.peek_stack(1)?) {
+                    (Value::String(b), Value::String(a)) => {
+                        // Both operands stay on the stack while the result is
+                        // allocated, so that a collection can't free them.
+                        let string = self.intern_string(format!("{}{}", a, b));
+                        self.stack.truncate(self.stack.len() - 2);
+                        self.push_stack(Value::String(string));
+                    }
+                    (Value::Number(b), Value::Number(a)) => {
+                        self.stack.truncate(self.stack.len() - 2);
+                        self.push_stack(Value::Number(a + b));
+                    }
+                    _ => {
+                        return Err(RuntimeError::TypeError(
+                            "Operands must be two numbers or two strings.",
+                        ))
+                    }
+                },
+                OpCode::Substract => binary_op!(Value::Number, -),
+                OpCode::Multiply => binary_op!(Value::Number, *),
+                OpCode::Divide => binary_op!(Value::Number, /),
+                OpCode::Not => {
+                    let value = self.pop_stack()?;
+                    self.push_stack(Value::Bool(value.is_falsey()));
+                }
                 OpCode::Negate => {
                     let value = self.pop_stack()?;
                     match value {
                         Value::Number(val) => self.push_stack(Value::Number(-val)),
+                        _ => return Err(RuntimeError::TypeError("Operand must be a number.")),
                     }
                 }
-                OpCode::Return => {
+                OpCode::Print => {
                     let value = self.pop_stack()?;
-                    println!("{}", value);
-                    return Ok(());
+                    writeln!(self.output, "{}", value)
+                        .map_err(|err| RuntimeError::OutputError(err.kind()))?;
                 }
+                OpCode::Jump => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    if self.peek_stack(0)?.is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                }
+                OpCode::ConstantLong => {
+                    let idx = self.read_long();
+                    let constant = self.read_constant(idx)?;
+                    self.push_stack(constant);
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte();
+                    let callee = self.peek_stack(arg_count as usize)?;
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    let idx = self.read_index(instruction);
+                    let arg_count = self.read_byte();
+                    let name = self.read_string(idx)?;
+                    self.invoke(name, arg_count)?;
+                }
+                OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                    let idx = self.read_index(instruction);
+                    let arg_count = self.read_byte();
+                    let name = self.read_string(idx)?;
+                    let superclass = match self.pop_stack()? {
+                        Value::Class(class) => class,
+                        _ => return Err(RuntimeError::InvalidChunkError),
+                    };
+
+                    self.invoke_from_class(superclass, name, arg_count)?;
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let idx = self.read_index(instruction);
+                    let function = match self.read_constant(idx)? {
+                        Value::Function(function) => function,
+                        _ => return Err(RuntimeError::InvalidChunkError),
+                    };
+
+                    let frame = *self.frame();
+                    let mut upvalues = Vec::with_capacity(function.upvalues.len());
+                    for upvalue in &function.upvalues {
+                        let index = upvalue.index as usize;
+                        let upvalue = if upvalue.is_local {
+                            self.capture_upvalue(frame.slot + index)
+                        } else {
+                            *frame
+                                .closure
+                                .upvalues
+                                .get(index)
+                                .ok_or(RuntimeError::InvalidChunkError)?
+                        };
+                        upvalues.push(upvalue);
+                    }
+
+                    let closure = self.alloc(Closure::new(function, upvalues));
+                    self.push_stack(Value::Closure(closure));
+                }
+                OpCode::GetUpvalue => {
+                    let slot = self.read_byte();
+                    let value = match self.upvalue(slot)?.state() {
+                        UpvalueState::Open(slot) => self.stack[slot],
+                        UpvalueState::Closed(value) => value,
+                    };
+                    self.push_stack(value);
+                }
+                OpCode::SetUpvalue => {
+                    let slot = self.read_byte();
+                    let value = self.peek_stack(0)?;
+                    let upvalue = self.upvalue(slot)?;
+                    match upvalue.state() {
+                        UpvalueState::Open(slot) => self.stack[slot] = value,
+                        UpvalueState::Closed(_) => upvalue.set_state(UpvalueState::Closed(value)),
+                    }
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop_stack()?;
+                }
+                OpCode::Return => {
+                    let result = self.pop_stack()?;
+                    let frame = self.frames.pop().ok_or(RuntimeError::InvalidChunkError)?;
+
+                    self.close_upvalues(frame.slot);
+                    self.stack.truncate(frame.slot);
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                    self.push_stack(result);
+                }
+                OpCode::Class | OpCode::ClassLong => {
+                    let idx = self.read_index(instruction);
+                    let name = self.read_string(idx)?;
+                    let class = self.alloc(Class::new(name));
+                    self.push_stack(Value::Class(class));
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.peek_stack(1)? {
+                        Value::Class(class) => class,
+                        _ => return Err(RuntimeError::SuperclassNotClass),
+                    };
+                    let subclass = match self.peek_stack(0)? {
+                        Value::Class(class) => class,
+                        _ => return Err(RuntimeError::InvalidChunkError),
+                    };
+
+                    // Methods are copied down before the subclass defines its
+                    // own, which then override them.
+                    let methods = superclass.methods.borrow().clone();
+                    self.heap.update(subclass, |subclass| {
+                        subclass.methods.borrow_mut().extend(methods)
+                    });
+                    self.pop_stack()?;
+                }
+                OpCode::Method | OpCode::MethodLong => {
+                    let idx = self.read_index(instruction);
+                    let name = self.read_string(idx)?;
+                    match (self.peek_stack(1)?, self.peek_stack(0)?) {
+                        (Value::Class(class), Value::Closure(method)) => {
+                            self.heap.update(class, |class| {
+                                class.methods.borrow_mut().insert(name, method)
+                            });
+                        }
+                        _ => return Err(RuntimeError::InvalidChunkError),
+                    }
+                    self.pop_stack()?;
+                }
+            }
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), RuntimeError> {
+        match callee {
+            Value::Closure(closure) => self.call(closure, arg_count),
+            Value::Class(class) => {
+                // The class is still on the stack, so it survives allocating
+                // the instance that replaces it.
+                let instance = self.alloc(Instance::new(class));
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = Value::Instance(instance);
+
+                match class.method(self.init_string) {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => Err(RuntimeError::ArityMismatch {
+                        expected: 0,
+                        got: arg_count as usize,
+                    }),
+                    None => Ok(()),
+                }
+            }
+            Value::BoundMethod(bound_method) => {
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = bound_method.receiver;
+                self.call(bound_method.method, arg_count)
+            }
+            Value::NativeFn(native) => self.call_native(native, arg_count),
+            _ => Err(RuntimeError::NotCallable),
+        }
+    }
+
+    /// Calls the method `name` of the receiver below the arguments on the
+    /// stack, without allocating a bound method.
+    fn invoke(&mut self, name: Gc<LoxString>, arg_count: u8) -> Result<(), RuntimeError> {
+        let instance = match self.peek_stack(arg_count as usize)? {
+            Value::Instance(instance) => instance,
+            _ => return Err(RuntimeError::NotAnInstance),
+        };
+
+        // Fields shadow methods, and may hold any callable value.
+        if let Some(value) = instance.field(name) {
+            let slot = self.stack.len() - arg_count as usize - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: Gc<Class>,
+        name: Gc<LoxString>,
+        arg_count: u8,
+    ) -> Result<(), RuntimeError> {
+        let method = class
+            .method(name)
+            .ok_or_else(|| RuntimeError::UndefinedProperty(name.to_string()))?;
+
+        self.call(method, arg_count)
+    }
+
+    /// Replaces the instance on top of the stack by its method `name`, bound
+    /// to it.
+    fn bind_method(&mut self, class: Gc<Class>, name: Gc<LoxString>) -> Result<(), RuntimeError> {
+        let method = class
+            .method(name)
+            .ok_or_else(|| RuntimeError::UndefinedProperty(name.to_string()))?;
+
+        let receiver = self.peek_stack(0)?;
+        let bound_method = self.alloc(BoundMethod::new(receiver, method));
+        self.pop_stack()?;
+        self.push_stack(Value::BoundMethod(bound_method));
+
+        Ok(())
+    }
+
+    fn call(&mut self, closure: Gc<Closure>, arg_count: u8) -> Result<(), RuntimeError> {
+        let function = closure.function;
+        let arg_count = arg_count as usize;
+
+        if arg_count != function.arity {
+            return Err(RuntimeError::ArityMismatch {
+                expected: function.arity,
+                got: arg_count,
+            });
+        }
+
+        if self.frames.len() >= self.max_frames {
+            return Err(RuntimeError::StackOverflow);
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slot: self.stack.len() - arg_count - 1,
+        });
+
+        Ok(())
+    }
+
+    fn call_native(&mut self, native: Gc<NativeFn>, arg_count: u8) -> Result<(), RuntimeError> {
+        let arg_count = arg_count as usize;
+
+        if arg_count != native.arity {
+            return Err(RuntimeError::ArityMismatch {
+                expected: native.arity,
+                got: arg_count,
+            });
+        }
+
+        // Arguments stay on the stack during the call, so that they survive
+        // any allocation made by the native.
+        let args_start = self.stack.len() - arg_count;
+        let args: Vec<_> = self.stack[args_start..]
+            .iter()
+            .map(|&arg| NativeValue::new(arg))
+            .collect();
+        let result = (native.function)(&mut NativeCall::new(self), &args)?.value();
+
+        self.stack.truncate(args_start - 1);
+        self.push_stack(result);
+
+        Ok(())
+    }
+
+    /// Returns the upvalue capturing the given stack slot, creating it if no
+    /// closure captured that variable yet.
+    fn capture_upvalue(&mut self, slot: usize) -> Gc<Upvalue> {
+        let mut insert_at = self.open_upvalues.len();
+
+        for (idx, upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            match upvalue.state() {
+                UpvalueState::Open(open_slot) if open_slot == slot => return *upvalue,
+                UpvalueState::Open(open_slot) if open_slot < slot => break,
+                _ => insert_at = idx,
             }
         }
+
+        let upvalue = self.alloc(Upvalue::new(slot));
+        self.open_upvalues.insert(insert_at, upvalue);
+
+        upvalue
+    }
+
+    /// Moves every variable from `last_slot` upward off the stack and into the
+    /// upvalues capturing it.
+    fn close_upvalues(&mut self, last_slot: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            match upvalue.state() {
+                UpvalueState::Open(slot) if slot >= last_slot => {
+                    upvalue.set_state(UpvalueState::Closed(self.stack[slot]));
+                    self.open_upvalues.pop();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Allocates an object on the heap, collecting garbage first if needed.
+    ///
+    /// Every object the VM still uses must be reachable from its roots when
+    /// calling this, as anything else may be freed.
+    fn alloc<T: Trace + 'static>(&mut self, value: T) -> Gc<T> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        self.heap.alloc(value)
+    }
+
+    /// Returns the interned string with the given contents, for natives to
+    /// build string values. The string is kept on the stack, which the
+    /// current native call truncates when it returns.
+    pub(crate) fn root_string(&mut self, value: String) -> Gc<LoxString> {
+        let string = self.intern_string(value);
+        self.push_stack(Value::String(string));
+
+        string
+    }
+
+    fn intern_string(&mut self, value: String) -> Gc<LoxString> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        self.heap.take_string(value)
+    }
+
+    fn collect_garbage(&mut self) {
+        for &value in &self.stack {
+            self.heap.mark_value(value);
+        }
+        for frame in &self.frames {
+            self.heap.mark(frame.closure);
+        }
+        for (&name, &value) in &self.globals {
+            self.heap.mark(name);
+            self.heap.mark_value(value);
+        }
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark(upvalue);
+        }
+        self.heap.mark(self.init_string);
+
+        self.heap.collect();
+    }
+
+    fn upvalue(&self, slot: u8) -> Result<Gc<Upvalue>, RuntimeError> {
+        self.frame()
+            .closure
+            .upvalues
+            .get(slot as usize)
+            .cloned()
+            .ok_or(RuntimeError::InvalidChunkError)
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no call frame")
+    }
+
+    fn trace_instruction(&mut self, function: Gc<Function>, offset: usize) -> io::Result<()> {
+        if let Some(filter) = &self.trace_function {
+            let name = function
+                .name
+                .as_ref()
+                .map_or("<script>", |name| name.as_str());
+            if name != filter {
+                return Ok(());
+            }
+        }
+
+        let trace = match &mut self.trace {
+            Some(trace) => trace,
+            None => return Ok(()),
+        };
+
+        write!(trace, "          ")?;
+        for slot in &self.stack {
+            write!(trace, "[ {} ]", slot)?;
+        }
+        writeln!(trace)?;
+        disassemble_instruction(&function.chunk, offset, trace)?;
+
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.closure.function.chunk.read_byte(frame.ip);
+        frame.ip += 1;
+
+        byte
+    }
+
+    fn read_short(&mut self) -> u16 {
+        let frame = self.frame_mut();
+        let short = frame.closure.function.chunk.read_short(frame.ip);
+        frame.ip += 2;
+
+        short
+    }
+
+    fn read_long(&mut self) -> usize {
+        let frame = self.frame_mut();
+        let long = frame.closure.function.chunk.read_long(frame.ip);
+        frame.ip += 3;
+
+        long
+    }
+
+    /// Reads the constant index operand of `instruction`, in its short or
+    /// long form.
+    fn read_index(&mut self, instruction: OpCode) -> usize {
+        if instruction.is_long() {
+            self.read_long()
+        } else {
+            self.read_byte() as usize
+        }
+    }
+
+    fn read_constant(&self, idx: usize) -> Result<Value, RuntimeError> {
+        self.frame()
+            .closure
+            .function
+            .chunk
+            .constant_at(idx)
+            .ok_or(RuntimeError::InvalidChunkError)
+    }
+
+    fn read_string(&self, idx: usize) -> Result<Gc<LoxString>, RuntimeError> {
+        match self.read_constant(idx)? {
+            Value::String(string) => Ok(string),
+            _ => Err(RuntimeError::InvalidChunkError),
+        }
+    }
+
+    fn stack_slot(&self, slot: u8) -> Result<Value, RuntimeError> {
+        self.stack
+            .get(self.frame().slot + slot as usize)
+            .cloned()
+            .ok_or(RuntimeError::InvalidChunkError)
+    }
+
+    fn peek_stack(&self, distance: usize) -> Result<Value, RuntimeError> {
+        self.stack
+            .len()
+            .checked_sub(distance + 1)
+            .map(|idx| self.stack[idx])
+            .ok_or(RuntimeError::StackUnderflow)
     }
 
     fn pop_stack(&mut self) -> Result<Value, RuntimeError> {
@@ -118,3 +925,706 @@ impl Vm {
         self.stack.push(value);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{compiler::compile, verifier::VerifyErrorKind};
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(source: &str) -> (InterpretResult, String) {
+        run_with_heap(source, Heap::new())
+    }
+
+    fn run_with_heap(source: &str, mut heap: Heap) -> (InterpretResult, String) {
+        let chunk = compile(source, &mut heap).unwrap();
+        let mut vm = Vm::init(chunk, heap).unwrap();
+        let output = Output::default();
+        vm.set_output(output.clone());
+
+        let result = vm.interpret();
+        let output = String::from_utf8(output.0.take()).unwrap();
+
+        (result, output)
+    }
+
+    fn output(source: &str) -> String {
+        let (result, output) = run(source);
+        assert!(result.is_ok(), "{:?}", result);
+        output
+    }
+
+    fn runtime_error(source: &str) -> RuntimeError {
+        match run(source).0 {
+            Err(InterpretError::RuntimeError { error, .. }) => error,
+            result => panic!("expected a runtime error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(output("print -(1 + 2) * 3 / 4;"), "-2.25\n");
+        assert_eq!(output("print 13.37 + 42;"), "55.37\n");
+    }
+
+    #[test]
+    fn comparison() {
+        assert_eq!(output("print !(5 - 4 > 3 * 2 == !nil);"), "true\n");
+        assert_eq!(output("print 1 <= 2 != false;"), "true\n");
+        assert_eq!(output("print nil == false;"), "false\n");
+    }
+
+    #[test]
+    fn concatenation() {
+        assert_eq!(output(r#"print "a" + "b" + "c" == "abc";"#), "true\n");
+        assert_eq!(output(r#"print "lox" + "-" + "rs";"#), "lox-rs\n");
+        assert!(matches!(
+            runtime_error(r#""a" + 1;"#),
+            RuntimeError::TypeError(_)
+        ));
+    }
+
+    #[test]
+    fn globals() {
+        let source = "var a = 42; var b = a + 1; var c; b = b * 2; print a; print b; print c;";
+        assert_eq!(output(source), "42\n86\nnil\n");
+    }
+
+    #[test]
+    fn long_constants() {
+        let mut source: String = (0..300).map(|i| format!("{};", i)).collect();
+        source.push_str("print 299;");
+        assert_eq!(output(&source), "299\n");
+    }
+
+    #[test]
+    fn long_names() {
+        let literals: String = (0..300).map(|i| format!("{};", i)).collect();
+        let source = format!(
+            "{literals}
+            var a = 1;
+            a = a + 1;
+            print a;
+            fun f() {{ return a; }}
+            print f();
+            class A {{
+                init() {{ this.x = 3; }}
+                m() {{ return this.x; }}
+            }}
+            class B < A {{
+                m() {{
+                    {literals}
+                    var m = super.m;
+                    return super.m() + m();
+                }}
+            }}
+            var b = B();
+            b.x = 4;
+            print b.x;
+            print b.m();",
+            literals = literals
+        );
+
+        assert_eq!(output(&source), "2\n2\n4\n8\n");
+    }
+
+    #[test]
+    fn locals() {
+        let source =
+            "var a = 1; { var b = a + 1; { var c = b * 10; var a = c; print a; } } print a;";
+        assert_eq!(output(source), "20\n1\n");
+    }
+
+    #[test]
+    fn expression_statement() {
+        let mut heap = Heap::new();
+        let chunk = compile("1 + 2; { var a = 3; a = a * 2; }", &mut heap).unwrap();
+        let mut vm = Vm::init(chunk, heap).unwrap();
+
+        assert!(vm.interpret().is_ok());
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn control_flow() {
+        let source = "
+            var a = 0;
+            if (a > 0) print \"positive\"; else print \"not positive\";
+            if (a == 0) print \"zero\";
+            while (a < 3) a = a + 1;
+            print a;
+            for (var i = 0; i < 3; i = i + 1) print i;
+            var b = 5;
+            for (; b > 3;) b = b - 1;
+            print b;
+        ";
+        assert_eq!(output(source), "not positive\nzero\n3\n0\n1\n2\n3\n");
+    }
+
+    #[test]
+    fn logical_operators() {
+        assert_eq!(output("print nil or \"default\";"), "default\n");
+        assert_eq!(output("print 1 and 2;"), "2\n");
+        assert_eq!(output("print false and undefined;"), "false\n");
+        assert_eq!(output("print true or undefined;"), "true\n");
+        assert_eq!(output("print (nil or false) and true;"), "false\n");
+    }
+
+    #[test]
+    fn functions() {
+        let source = std::fs::read_to_string("tests/calc.lox").unwrap();
+        assert_eq!(output(&source), "55.37\n");
+
+        let source = "
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 2) + fib(n - 1);
+            }
+            fun nothing() {}
+            print fib(15);
+            print nothing();
+            print fib;
+        ";
+        assert_eq!(output(source), "610\nnil\n<fn fib>\n");
+    }
+
+    #[test]
+    fn closures() {
+        let source = "
+            fun make_counter() {
+                var count = 0;
+                fun increment() {
+                    count = count + 1;
+                    return count;
+                }
+                return increment;
+            }
+            var a = make_counter();
+            var b = make_counter();
+            print a();
+            print a();
+            print b();
+            print a;
+        ";
+        assert_eq!(output(source), "1\n2\n1\n<fn increment>\n");
+    }
+
+    #[test]
+    fn shared_upvalues() {
+        let source = "
+            var get;
+            var set;
+            fun main() {
+                var value = \"before\";
+                fun getter() { return value; }
+                fun setter(new_value) { value = new_value; }
+                get = getter;
+                set = setter;
+                print get();
+                value = \"local\";
+                print get();
+            }
+            main();
+            set(\"closed\");
+            print get();
+        ";
+        assert_eq!(output(source), "before\nlocal\nclosed\n");
+    }
+
+    #[test]
+    fn nested_closures() {
+        let source = "
+            fun outer() {
+                var x = \"outer\";
+                fun middle() {
+                    fun inner() { return x; }
+                    return inner;
+                }
+                return middle;
+            }
+            print outer()()();
+            {
+                var a = 1;
+                fun f() { return a; }
+                a = 2;
+                print f();
+            }
+        ";
+        assert_eq!(output(source), "outer\n2\n");
+    }
+
+    #[test]
+    fn call_errors() {
+        assert!(matches!(
+            runtime_error("fun f(a, b) {} f(1);"),
+            RuntimeError::ArityMismatch {
+                expected: 2,
+                got: 1
+            }
+        ));
+        assert!(matches!(
+            runtime_error("var f = 1; f();"),
+            RuntimeError::NotCallable
+        ));
+        assert!(matches!(
+            runtime_error("fun f() { f(); } f();"),
+            RuntimeError::StackOverflow
+        ));
+    }
+
+    #[test]
+    fn max_frames() {
+        let mut heap = Heap::new();
+        let source = "fun f(n) { if (n > 0) f(n - 1); } f(8);";
+        let chunk = compile(source, &mut heap).unwrap();
+        let mut vm = Vm::init(chunk, heap).unwrap();
+        vm.set_max_frames(8);
+
+        assert!(matches!(
+            vm.interpret(),
+            Err(InterpretError::RuntimeError {
+                error: RuntimeError::StackOverflow,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn undefined_variable() {
+        assert!(matches!(
+            runtime_error("var a = 1; print b;"),
+            RuntimeError::UndefinedVariable(name) if name == "b"
+        ));
+        assert!(matches!(
+            runtime_error("b = 1;"),
+            RuntimeError::UndefinedVariable(name) if name == "b"
+        ));
+    }
+
+    #[test]
+    fn type_error() {
+        assert!(matches!(
+            runtime_error("-true;"),
+            RuntimeError::TypeError("Operand must be a number.")
+        ));
+        assert!(matches!(
+            runtime_error("1 < nil;"),
+            RuntimeError::TypeError("Operands must be numbers.")
+        ));
+        assert!(matches!(
+            runtime_error("1 + nil;"),
+            RuntimeError::TypeError("Operands must be two numbers or two strings.")
+        ));
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(output("class A {} print A; print A();"), "A\nA instance\n");
+        assert_eq!(
+            output("class A {} var a = A(); a.x = 1; a.y = a.x + 1; print a.y;"),
+            "2\n"
+        );
+
+        let source = r#"
+            class Counter {
+                init(start) {
+                    this.count = start;
+                    return;
+                }
+                increment() {
+                    this.count = this.count + 1;
+                    return this;
+                }
+            }
+            var counter = Counter(1);
+            counter.increment().increment();
+            print counter.count;
+            print counter.init(5) == counter;
+            print counter.count;
+        "#;
+        assert_eq!(output(source), "3\ntrue\n5\n");
+    }
+
+    #[test]
+    fn bound_methods() {
+        let source = r#"
+            class Person {
+                init(name) { this.name = name; }
+                greeter() {
+                    fun greet() { print "hi " + this.name; }
+                    return greet;
+                }
+                say() { print this.name; }
+            }
+            var say = Person("jane").say;
+            say();
+            Person("joe").greeter()();
+            print say;
+        "#;
+        assert_eq!(output(source), "jane\nhi joe\n<fn say>\n");
+    }
+
+    #[test]
+    fn property_errors() {
+        assert!(matches!(
+            runtime_error("class A {} A().missing;"),
+            RuntimeError::UndefinedProperty(name) if name == "missing"
+        ));
+        assert!(matches!(
+            runtime_error("var a = 1; a.b;"),
+            RuntimeError::NotAnInstance
+        ));
+        assert!(matches!(
+            runtime_error("var a = 1; a.b = 2;"),
+            RuntimeError::NotAnInstance
+        ));
+        assert!(matches!(
+            runtime_error("class A {} A(1);"),
+            RuntimeError::ArityMismatch {
+                expected: 0,
+                got: 1
+            }
+        ));
+        assert!(matches!(
+            runtime_error("class A { init(a) {} } A();"),
+            RuntimeError::ArityMismatch {
+                expected: 1,
+                got: 0
+            }
+        ));
+    }
+
+    #[test]
+    fn inheritance() {
+        let source = r#"
+            class A {
+                init(name) { this.name = name; }
+                method() { return "A " + this.name; }
+                other() { return "A other"; }
+            }
+            class B < A {
+                init(name) { super.init(name + "!"); }
+                method() { return "B " + super.method(); }
+                bound() { return super.other; }
+            }
+            var b = B("b");
+            print b.method();
+            print b.other();
+            print b.bound()();
+        "#;
+        assert_eq!(output(source), "B A b!\nA other\nA other\n");
+    }
+
+    #[test]
+    fn invoke() {
+        let source = r#"
+            class A {
+                method() { return "method"; }
+            }
+            fun f() { return "field"; }
+            var a = A();
+            print a.method();
+            a.method = f;
+            print a.method();
+        "#;
+        assert_eq!(output(source), "method\nfield\n");
+        assert!(matches!(
+            runtime_error("class A {} A().missing();"),
+            RuntimeError::UndefinedProperty(name) if name == "missing"
+        ));
+        assert!(matches!(
+            runtime_error("var a = 1; a.method();"),
+            RuntimeError::NotAnInstance
+        ));
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        assert!(matches!(
+            runtime_error("var A = 1; class B < A {}"),
+            RuntimeError::SuperclassNotClass
+        ));
+        assert!(matches!(
+            runtime_error("class A {} class B < A { f() { super.missing(); } } B().f();"),
+            RuntimeError::UndefinedProperty(name) if name == "missing"
+        ));
+    }
+
+    #[test]
+    fn natives() {
+        fn add<'a>(
+            _call: &mut NativeCall<'a>,
+            args: &[NativeValue<'a>],
+        ) -> Result<NativeValue<'a>, RuntimeError> {
+            match (args[0].as_number(), args[1].as_number()) {
+                (Some(a), Some(b)) => Ok(NativeValue::number(a + b)),
+                _ => Err(RuntimeError::TypeError("Operands must be numbers.")),
+            }
+        }
+
+        fn greet<'a>(
+            call: &mut NativeCall<'a>,
+            args: &[NativeValue<'a>],
+        ) -> Result<NativeValue<'a>, RuntimeError> {
+            // Under stress, creating the second string collects garbage,
+            // which must keep the first one alive.
+            let hello = call.string("hello".to_string());
+            let name = call.string(args[0].to_string());
+            Ok(call.string(format!("{} {}", hello, name)))
+        }
+
+        let run_with_natives = |source| {
+            let mut heap = Heap::new();
+            heap.set_stress(true);
+            let chunk = compile(source, &mut heap).unwrap();
+            let mut vm = Vm::init(chunk, heap).unwrap();
+            vm.define_native("add", 2, add);
+            vm.define_native("greet", 1, greet);
+            let output = Output::default();
+            vm.set_output(output.clone());
+
+            let result = vm.interpret();
+            (result, String::from_utf8(output.0.take()).unwrap())
+        };
+
+        let (result, output) =
+            run_with_natives("print add(1, 2) + 3; print greet(\"lox\"); print add;");
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(output, "6\nhello lox\n<native fn add>\n");
+
+        assert!(matches!(
+            run_with_natives("add(1);").0,
+            Err(InterpretError::RuntimeError {
+                error: RuntimeError::ArityMismatch {
+                    expected: 2,
+                    got: 1
+                },
+                ..
+            })
+        ));
+        assert!(matches!(
+            run_with_natives("add(1, nil);").0,
+            Err(InterpretError::RuntimeError {
+                error: RuntimeError::TypeError(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn stdlib() {
+        assert_eq!(
+            output(r#"print len(str(12) + "3") + num("4"); print type(clock);"#),
+            "7\nfunction\n"
+        );
+        assert!(matches!(
+            runtime_error("sqrt(nil);"),
+            RuntimeError::InvalidArgument {
+                function: "sqrt",
+                ..
+            }
+        ));
+
+        let mut heap = Heap::new();
+        let chunk = compile("print clock;", &mut heap).unwrap();
+        let mut vm = Vm::init_bare(chunk, heap).unwrap();
+        assert!(matches!(
+            vm.interpret(),
+            Err(InterpretError::RuntimeError {
+                error: RuntimeError::UndefinedVariable(name),
+                ..
+            }) if name == "clock"
+        ));
+    }
+
+    #[test]
+    fn stack_trace() {
+        let source = "fun a() {\n  b();\n}\nfun b() {\n  c();\n}\n\na();";
+        let error = run(source).0.unwrap_err();
+
+        match &error {
+            InterpretError::RuntimeError {
+                line,
+                column,
+                trace,
+                ..
+            } => {
+                assert_eq!((*line, *column), (5, 3));
+                assert_eq!(
+                    trace,
+                    &vec![
+                        TraceFrame {
+                            function: Some(String::from("b")),
+                            line: 5,
+                            column: 3,
+                        },
+                        TraceFrame {
+                            function: Some(String::from("a")),
+                            line: 2,
+                            column: 4,
+                        },
+                        TraceFrame {
+                            function: None,
+                            line: 8,
+                            column: 2,
+                        },
+                    ]
+                );
+            }
+            error => panic!("expected a runtime error, got {:?}", error),
+        }
+        assert_eq!(
+            error.to_string(),
+            "Undefined variable 'c'.\n[line 5] in b()\n[line 2] in a()\n[line 8] in script"
+        );
+    }
+
+    #[test]
+    fn invalid_chunk() {
+        let mut heap = Heap::new();
+        let mut chunk = compile("print 1;", &mut heap).unwrap();
+        chunk.code.pop();
+
+        let error = Vm::init(chunk, heap).err().unwrap();
+        assert_eq!(error.kind, VerifyErrorKind::MissingReturn);
+        assert_eq!(
+            InterpretError::from(error).to_string(),
+            "Invalid bytecode at offset 3 in script: Missing return at end of chunk."
+        );
+    }
+
+    #[test]
+    fn trace() {
+        let mut heap = Heap::new();
+        let chunk = compile("fun f(a) {\n  return -a;\n}\nprint f(1);", &mut heap).unwrap();
+        let mut vm = Vm::init(chunk, heap).unwrap();
+        let output = Output::default();
+        let trace = Output::default();
+        vm.set_output(output.clone());
+        vm.set_trace(trace.clone());
+        vm.set_trace_function("f");
+
+        assert!(vm.interpret().is_ok());
+        assert_eq!(String::from_utf8(output.0.take()).unwrap(), "-1\n");
+        assert_eq!(
+            String::from_utf8(trace.0.take()).unwrap(),
+            "          [ <script> ][ <fn f> ][ 1 ]
+0000    2 OP_GET_LOCAL        1
+          [ <script> ][ <fn f> ][ 1 ][ 1 ]
+0002    | OP_NEGATE
+          [ <script> ][ <fn f> ][ 1 ][ -1 ]
+0003    | OP_RETURN
+"
+        );
+    }
+
+    #[test]
+    fn finished() {
+        for source in &["print 1;", "print -nil;"] {
+            let mut heap = Heap::new();
+            let chunk = compile(source, &mut heap).unwrap();
+            let mut vm = Vm::init(chunk, heap).unwrap();
+            vm.set_output(Output::default());
+
+            vm.interpret().ok();
+            assert!(matches!(
+                vm.interpret(),
+                Err(InterpretError::RuntimeError {
+                    error: RuntimeError::Finished,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn trace_script() {
+        let traced = |function: &str| {
+            let mut heap = Heap::new();
+            let chunk = compile("fun script() { return 1; } script();", &mut heap).unwrap();
+            let mut vm = Vm::init(chunk, heap).unwrap();
+            let trace = Output::default();
+            vm.set_trace(trace.clone());
+            vm.set_trace_function(function);
+
+            assert!(vm.interpret().is_ok());
+            String::from_utf8(trace.0.take()).unwrap()
+        };
+
+        let top_level = traced("<script>");
+        assert!(top_level.contains("OP_CALL"));
+        assert!(!top_level.contains("OP_CONSTANT"));
+
+        let function = traced("script");
+        assert!(function.contains("OP_CONSTANT"));
+        assert!(!function.contains("OP_CALL"));
+    }
+
+    #[test]
+    fn stress_gc() {
+        let mut heap = Heap::new();
+        heap.set_stress(true);
+        let source = r#"
+            fun makeCounter(name) {
+                var count = 0;
+                fun counter() {
+                    count = count + 1;
+                    return name + ": " + "tick";
+                }
+                return counter;
+            }
+            class Pair {
+                init(a, b) { this.a = a; this.b = b; }
+                tick() { return this.a() + " " + this.b(); }
+            }
+            var pair = Pair(makeCounter("a"), makeCounter("b"));
+            for (var i = 0; i < 3; i = i + 1) {
+                print pair.tick();
+            }
+        "#;
+
+        let (result, output) = run_with_heap(source, heap);
+
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(output, "a: tick b: tick\n".repeat(3));
+    }
+
+    #[test]
+    fn collect_garbage() {
+        let source = r#"
+            var kept = "kept";
+            for (var i = 0; i < 100; i = i + 1) {
+                var garbage = "garbage" + kept;
+                fun closure() { return garbage; }
+            }
+        "#;
+        let mut heap = Heap::new();
+        let chunk = compile(source, &mut heap).unwrap();
+        let mut vm = Vm::init_bare(chunk, heap).unwrap();
+        vm.interpret().unwrap();
+
+        let bytes_allocated = vm.heap.bytes_allocated();
+        vm.collect_garbage();
+
+        assert!(vm.heap.bytes_allocated() < bytes_allocated);
+        assert_eq!(vm.globals.len(), 1);
+        assert!(vm.globals.values().all(|value| match value {
+            Value::String(string) => string.as_str() == "kept",
+            _ => false,
+        }));
+    }
+}
@@ -0,0 +1,82 @@
+use std::{cell::RefCell, io, rc::Rc};
+
+use lox::{
+    native::{NativeCall, NativeValue},
+    vm::{InterpretError, RuntimeError, Vm},
+};
+
+/// Output shared with the VM, to read what the program printed.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn repeat<'a>(
+    call: &mut NativeCall<'a>,
+    args: &[NativeValue<'a>],
+) -> Result<NativeValue<'a>, RuntimeError> {
+    match (args[0].as_str(), args[1].as_number()) {
+        (Some(string), Some(count)) if count >= 0.0 => {
+            Ok(call.string(string.repeat(count as usize)))
+        }
+        _ => Err(RuntimeError::InvalidArgument {
+            function: "repeat",
+            expected: "a string and a count",
+        }),
+    }
+}
+
+#[test]
+fn natives_and_output() {
+    let mut vm = Vm::new(r#"print repeat("ab", 3); print len(repeat("x", 4));"#).unwrap();
+    vm.define_native("repeat", 2, repeat);
+    let output = Output::default();
+    vm.set_output(output.clone());
+
+    assert!(vm.interpret().is_ok());
+    assert_eq!(String::from_utf8(output.0.take()).unwrap(), "ababab\n4\n");
+}
+
+#[test]
+fn bare() {
+    let mut vm = Vm::new_bare("print len;").unwrap();
+
+    assert!(matches!(
+        vm.interpret(),
+        Err(InterpretError::RuntimeError {
+            error: RuntimeError::UndefinedVariable(_),
+            ..
+        })
+    ));
+    assert!(matches!(
+        Vm::new("print;"),
+        Err(InterpretError::CompileError(_))
+    ));
+}
+
+#[test]
+fn max_frames() {
+    let source = "fun f(n) { if (n > 0) f(n - 1); } f(20);";
+
+    let mut vm = Vm::new(source).unwrap();
+    vm.set_max_frames(10);
+    assert!(matches!(
+        vm.interpret(),
+        Err(InterpretError::RuntimeError {
+            error: RuntimeError::StackOverflow,
+            ..
+        })
+    ));
+
+    let mut vm = Vm::new(source).unwrap();
+    vm.set_max_frames(30);
+    assert!(vm.interpret().is_ok());
+}